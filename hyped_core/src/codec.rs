@@ -0,0 +1,92 @@
+use defmt::Format;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::mqtt::{Error, MAX_PAYLOAD_SIZE};
+
+/// A payload encoding. [`Json`] is readable and the default, [`Postcard`] is a
+/// compact binary encoding for high-rate data.
+pub trait Codec {
+    const CONTENT_TYPE: ContentType;
+
+    /// Serialises `message` into `buffer`, returning the length used.
+    fn encode<M: Serialize>(message: &M, buffer: &mut [u8]) -> Result<usize, Error>;
+
+    fn decode<M: DeserializeOwned>(payload: &[u8]) -> Result<M, Error>;
+}
+
+pub struct Json;
+
+impl Codec for Json {
+    const CONTENT_TYPE: ContentType = ContentType::Json;
+
+    fn encode<M: Serialize>(message: &M, buffer: &mut [u8]) -> Result<usize, Error> {
+        serde_json_core::to_slice(message, buffer).map_err(|_| Error::BufferOverflow)
+    }
+
+    fn decode<M: DeserializeOwned>(payload: &[u8]) -> Result<M, Error> {
+        // Strings with escapes are unescaped into this buffer. None of them
+        // can be longer than a payload.
+        let mut unescaped = [0; MAX_PAYLOAD_SIZE];
+        serde_json_core::from_slice_escaped(payload, &mut unescaped)
+            .map(|(message, _)| message)
+            .map_err(|_| Error::Decode)
+    }
+}
+
+pub struct Postcard;
+
+impl Codec for Postcard {
+    const CONTENT_TYPE: ContentType = ContentType::Postcard;
+
+    fn encode<M: Serialize>(message: &M, buffer: &mut [u8]) -> Result<usize, Error> {
+        postcard::to_slice(message, buffer)
+            .map(|used| used.len())
+            .map_err(|_| Error::BufferOverflow)
+    }
+
+    fn decode<M: DeserializeOwned>(payload: &[u8]) -> Result<M, Error> {
+        postcard::from_bytes(payload).map_err(|_| Error::Decode)
+    }
+}
+
+/// The encoding of a payload, carried as a suffix of the topic it is published
+/// on. JSON has no suffix, so its topics are the plain
+/// [`crate::mqtt_topics::MqttTopics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ContentType {
+    Json,
+    Postcard,
+}
+
+impl ContentType {
+    pub const ALL: [ContentType; 2] = [ContentType::Json, ContentType::Postcard];
+
+    pub fn topic_suffix(self) -> &'static str {
+        match self {
+            ContentType::Json => "",
+            ContentType::Postcard => "/postcard",
+        }
+    }
+
+    /// Strips the content type suffix from `topic`.
+    pub fn split_topic(topic: &str) -> (&str, ContentType) {
+        match topic.strip_suffix(ContentType::Postcard.topic_suffix()) {
+            Some(base) => (base, ContentType::Postcard),
+            None => (topic, ContentType::Json),
+        }
+    }
+
+    pub fn encode<M: Serialize>(self, message: &M, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            ContentType::Json => Json::encode(message, buffer),
+            ContentType::Postcard => Postcard::encode(message, buffer),
+        }
+    }
+
+    pub fn decode<M: DeserializeOwned>(self, payload: &[u8]) -> Result<M, Error> {
+        match self {
+            ContentType::Json => Json::decode(payload),
+            ContentType::Postcard => Postcard::decode(payload),
+        }
+    }
+}
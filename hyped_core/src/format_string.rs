@@ -1,36 +1,112 @@
 use core::cmp::min;
 use core::fmt;
 use defmt::error;
+use heapless::String;
+
+/// Appended to text cut short by a truncating [`FormatString`].
+pub const TRUNCATION_MARKER: &str = "…";
 
 pub struct FormatString<'a> {
     buffer: &'a mut [u8],
     // on write error (i.e. not enough space in buffer) this grows beyond
     // `buffer.len()`.
     used: usize,
+    truncate: bool,
+    truncated: bool,
 }
 
 impl<'a> FormatString<'a> {
     pub fn new(buffer: &'a mut [u8]) -> Self {
-        FormatString { buffer, used: 0 }
+        FormatString {
+            buffer,
+            used: 0,
+            truncate: false,
+            truncated: false,
+        }
+    }
+
+    /// Instead of failing when the buffer is full, keeps as much text as fits
+    /// followed by [`TRUNCATION_MARKER`]. Multibyte characters are never split.
+    pub fn truncating(buffer: &'a mut [u8]) -> Self {
+        FormatString {
+            truncate: true,
+            ..FormatString::new(buffer)
+        }
+    }
+
+    /// Whether text was cut short, only ever `true` in truncating mode.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Empties the buffer so it can be reused, keeping the mode.
+    pub fn reset(&mut self) {
+        self.used = 0;
+        self.truncated = false;
+    }
+
+    /// Replaces the contents with `args`, e.g. once per loop iteration.
+    pub fn format(&mut self, args: fmt::Arguments) -> Result<&str, fmt::Error> {
+        self.reset();
+        fmt::write(self, args)?;
+        self.text().ok_or(fmt::Error)
+    }
+
+    /// The text written so far, `None` after an overflow.
+    pub fn text(&self) -> Option<&str> {
+        contents(self.buffer, self.used)
     }
 
     pub fn as_str(self) -> Option<&'a str> {
-        if self.used <= self.buffer.len() {
-            // only successful concats of str - must be a valid str.
-            use core::str::from_utf8;
-            Some(from_utf8(&self.buffer[..self.used]).unwrap())
+        contents(self.buffer, self.used)
+    }
+
+    fn truncate(&mut self, s: &str) {
+        // Fill the buffer first, then cut back to leave room for the marker.
+        let free = self.buffer.len() - self.used;
+        self.buffer[self.used..].copy_from_slice(&s.as_bytes()[..free]);
+        let marker = TRUNCATION_MARKER.as_bytes();
+        let mut end = self.buffer.len().saturating_sub(marker.len());
+        while end > 0 && is_continuation_byte(self.buffer[end]) {
+            end -= 1;
+        }
+        if end + marker.len() <= self.buffer.len() {
+            self.buffer[end..end + marker.len()].copy_from_slice(marker);
+            self.used = end + marker.len();
         } else {
-            error!("FormatString buffer overflow");
-            None
+            self.used = end;
         }
+        self.truncated = true;
+    }
+}
+
+fn contents(buffer: &[u8], used: usize) -> Option<&str> {
+    if used <= buffer.len() {
+        // only successful concats of str - must be a valid str.
+        use core::str::from_utf8;
+        Some(from_utf8(&buffer[..used]).unwrap())
+    } else {
+        error!("FormatString buffer overflow");
+        None
     }
 }
 
+fn is_continuation_byte(byte: u8) -> bool {
+    byte & 0b1100_0000 == 0b1000_0000
+}
+
 impl<'a> fmt::Write for FormatString<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
         if self.used > self.buffer.len() {
             return Err(fmt::Error);
         }
+        if self.truncate && self.used + s.len() > self.buffer.len() {
+            self.truncate(s);
+            return Ok(());
+        }
         let remaining_buf = &mut self.buffer[self.used..];
         let raw_s = s.as_bytes();
         let write_num = min(raw_s.len(), remaining_buf.len());
@@ -49,3 +125,163 @@ pub fn show<'a>(buffer: &'a mut [u8], args: fmt::Arguments) -> Result<&'a str, f
     fmt::write(&mut w, args)?;
     w.as_str().ok_or(fmt::Error)
 }
+
+/// Like [`show`], but cuts the text short with [`TRUNCATION_MARKER`] instead
+/// of failing when it does not fit.
+pub fn show_truncated<'a>(buffer: &'a mut [u8], args: fmt::Arguments) -> &'a str {
+    let mut w = FormatString::truncating(buffer);
+    // Only a `Display` implementation can fail in truncating mode, which
+    // leaves the text written so far.
+    let _ = fmt::write(&mut w, args);
+    w.as_str().unwrap_or_default()
+}
+
+/// Formats into a `String` of at most `N` bytes, truncating like [`show_truncated`].
+pub fn show_string<const N: usize>(args: fmt::Arguments) -> String<N> {
+    let mut buffer = [0; N];
+    let mut string = String::new();
+    // Cannot fail, the text is at most `N` bytes long.
+    let _ = string.push_str(show_truncated(&mut buffer, args));
+    string
+}
+
+/// Formats into a `heapless::String`, truncating like [`show_truncated`]. The
+/// capacity comes from the type the result is assigned to:
+/// `let text: String<64> = hformat!("speed {}", speed);`
+#[macro_export]
+macro_rules! hformat {
+    ($($arg:tt)+) => {
+        $crate::format_string::show_string(format_args!($($arg)+))
+    };
+}
+
+/// Reuses a `FormatString`, replacing its contents:
+/// `let text = hwrite!(message, "speed {}", speed)?;`
+#[macro_export]
+macro_rules! hwrite {
+    ($target:expr, $($arg:tt)+) => {
+        $target.format(format_args!($($arg)+))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::{self, Write};
+    use heapless::String;
+
+    use super::{show, show_string, show_truncated, FormatString, TRUNCATION_MARKER};
+
+    #[test]
+    fn exact_fit_is_not_truncated() {
+        let mut buffer = [0; 5];
+        assert_eq!(show(&mut buffer, format_args!("{}", "hyped")), Ok("hyped"));
+        assert_eq!(
+            show_truncated(&mut buffer, format_args!("hyp{}", "ed")),
+            "hyped"
+        );
+
+        let mut text = FormatString::truncating(&mut buffer);
+        assert_eq!(hwrite!(text, "{}", "hyped"), Ok("hyped"));
+        assert!(!text.is_truncated());
+    }
+
+    #[test]
+    fn overflow_fails_without_truncation() {
+        let mut buffer = [0; 4];
+        assert_eq!(
+            show(&mut buffer, format_args!("{}", "hyped")),
+            Err(fmt::Error)
+        );
+
+        let mut text = FormatString::new(&mut buffer);
+        assert_eq!(hwrite!(text, "{}", "hyped"), Err(fmt::Error));
+        assert_eq!(text.text(), None);
+        // Once full every further write fails as well.
+        assert_eq!(text.write_str(""), Err(fmt::Error));
+    }
+
+    #[test]
+    fn overflow_ends_with_the_marker() {
+        let mut buffer = [0; 8];
+        let text = show_truncated(&mut buffer, format_args!("{} {}", "hyped", "pod"));
+        assert_eq!(text, ["hyped", TRUNCATION_MARKER].concat());
+        assert!(text.len() <= 8);
+
+        let string: String<8> = hformat!("{}{}", "hy", "ped pod");
+        assert_eq!(string, text);
+    }
+
+    #[test]
+    fn multibyte_characters_are_never_split() {
+        // "é" is two bytes and "€" three, so no cut lands on a boundary.
+        for len in 0..16 {
+            let mut buffer = [0; 16];
+            let text = show_truncated(&mut buffer[..len], format_args!("{}", "é€é€é€"));
+            assert!(text.len() <= len);
+            if len < "é€é€é€".len() {
+                assert!(text.is_empty() || text.ends_with(TRUNCATION_MARKER));
+            }
+            let kept = text.trim_end_matches(TRUNCATION_MARKER);
+            assert!("é€é€é€".starts_with(kept));
+        }
+    }
+
+    #[test]
+    fn buffer_smaller_than_the_marker_stays_empty() {
+        let mut buffer = [0; 2];
+        assert_eq!(show_truncated(&mut buffer, format_args!("{}", "hyped")), "");
+        let string: String<2> = show_string(format_args!("{}", "hyped"));
+        assert_eq!(string, "");
+    }
+
+    #[test]
+    fn writes_after_truncation_are_dropped() {
+        let mut buffer = [0; 8];
+        let mut text = FormatString::truncating(&mut buffer);
+        text.write_str("hyped pod").unwrap();
+        text.write_str("more").unwrap();
+        assert!(text.is_truncated());
+        assert_eq!(
+            text.text(),
+            Some(["hyped", TRUNCATION_MARKER].concat().as_str())
+        );
+    }
+
+    #[test]
+    fn reset_allows_reuse_and_keeps_the_mode() {
+        let mut buffer = [0; 8];
+        let mut text = FormatString::truncating(&mut buffer);
+        assert_eq!(
+            hwrite!(text, "{}", "hyped pod"),
+            Ok(["hyped", TRUNCATION_MARKER].concat().as_str())
+        );
+        assert!(text.is_truncated());
+        assert_eq!(hwrite!(text, "{}", "pod"), Ok("pod"));
+        assert!(!text.is_truncated());
+
+        text.reset();
+        assert_eq!(text.text(), Some(""));
+        text.write_str("hyped pod").unwrap();
+        assert!(text.is_truncated());
+
+        let mut buffer = [0; 4];
+        let mut text = FormatString::new(&mut buffer);
+        assert!(hwrite!(text, "{}", "hyped").is_err());
+        assert_eq!(hwrite!(text, "{}", "pod"), Ok("pod"));
+    }
+
+    #[test]
+    fn failing_display_keeps_the_text_so_far() {
+        struct Failing;
+        impl fmt::Display for Failing {
+            fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+        let mut buffer = [0; 16];
+        assert_eq!(
+            show_truncated(&mut buffer, format_args!("ok {}", Failing)),
+            "ok "
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::*;
+use heapless::{String, Vec};
+
+use crate::{mqtt::Error, mqtt_messages::HeartbeatMessage, mqtt_topics::MAX_BOARD_LENGTH};
+
+/// Board id the base station sends its heartbeats as.
+pub const BASE_STATION_BOARD: &str = "base-station";
+
+/// Collects check-ins from the tasks of a board and turns them into
+/// [`HeartbeatMessage`]s. Each task gets one bit of `tasks` and calls
+/// [`Heartbeat::check_in`] at least once per beat while it is healthy.
+pub struct Heartbeat<'a> {
+    board: &'a str,
+    tasks: u32,
+    checked_in: AtomicU32,
+    sequence: AtomicU32,
+}
+
+impl<'a> Heartbeat<'a> {
+    /// Panics if `board` is longer than [`MAX_BOARD_LENGTH`], which fails the
+    /// build for a heartbeat in a static.
+    pub const fn new(board: &'a str, tasks: u32) -> Self {
+        core::assert!(board.len() <= MAX_BOARD_LENGTH, "board id is too long");
+        Heartbeat {
+            board,
+            tasks,
+            checked_in: AtomicU32::new(0),
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    pub fn check_in(&self, task: u32) {
+        self.checked_in.fetch_or(task, Ordering::Relaxed);
+    }
+
+    /// The next heartbeat, which clears the check-ins for the next one.
+    pub fn beat(&self, uptime_ms: u64) -> HeartbeatMessage {
+        let mut board = String::new();
+        // Cannot fail, the length was checked by `new`.
+        let _ = board.push_str(self.board);
+        HeartbeatMessage {
+            board,
+            uptime_ms,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            tasks: self.tasks,
+            healthy: self.checked_in.swap(0, Ordering::Relaxed) & self.tasks,
+        }
+    }
+}
+
+/// What the monitor knows about one board.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardLiveness {
+    pub board: String<MAX_BOARD_LENGTH>,
+    /// When the last heartbeat arrived, or when watching started.
+    pub last_seen_ms: u64,
+    pub last_beat: Option<HeartbeatMessage>,
+    pub lost: bool,
+}
+
+impl BoardLiveness {
+    /// The bits of the tasks that did not check in before the last beat.
+    pub fn unhealthy_tasks(&self) -> u32 {
+        self.last_beat
+            .as_ref()
+            .map_or(0, |beat| beat.tasks & !beat.healthy)
+    }
+}
+
+/// Declares a board lost once it has missed `max_missed` heartbeats in a row.
+/// The caller decides what happens then, e.g. moving the pod to `Emergency`.
+pub struct LivenessMonitor<const BOARDS: usize> {
+    interval_ms: u32,
+    max_missed: u32,
+    boards: Vec<BoardLiveness, BOARDS>,
+}
+
+impl<const BOARDS: usize> LivenessMonitor<BOARDS> {
+    pub const fn new(interval_ms: u32, max_missed: u32) -> Self {
+        LivenessMonitor {
+            interval_ms,
+            max_missed,
+            boards: Vec::new(),
+        }
+    }
+
+    /// Expects heartbeats from `board` from now on, so that it is declared
+    /// lost even if it never sends one.
+    pub fn watch(&mut self, board: &str, now_ms: u64) -> Result<(), Error> {
+        if self.find(board).is_some() {
+            return Ok(());
+        }
+        self.boards
+            .push(BoardLiveness {
+                board: String::try_from(board).map_err(|_| Error::BufferOverflow)?,
+                last_seen_ms: now_ms,
+                last_beat: None,
+                lost: false,
+            })
+            .map_err(|_| Error::BufferOverflow)
+    }
+
+    /// Records a heartbeat, watching boards that are not known yet if there is
+    /// room. Returns whether a lost board is back.
+    pub fn record(&mut self, beat: &HeartbeatMessage, now_ms: u64) -> bool {
+        if self.watch(&beat.board, now_ms).is_err() {
+            debug!("Not watching board {}", beat.board.as_str());
+            return false;
+        }
+        let Some(board) = self.find(&beat.board) else {
+            return false;
+        };
+        if matches!(&board.last_beat, Some(last) if beat.sequence < last.sequence) {
+            info!("Board {} restarted", beat.board.as_str());
+        }
+        board.last_seen_ms = now_ms;
+        board.last_beat = Some(beat.clone());
+        core::mem::replace(&mut board.lost, false)
+    }
+
+    /// Declares boards lost that have not sent a heartbeat for `max_missed`
+    /// intervals, calling `on_lost` once for each of them. Boards stay lost,
+    /// see [`Self::is_lost`], until their next heartbeat.
+    pub fn check(&mut self, now_ms: u64, mut on_lost: impl FnMut(&BoardLiveness)) {
+        let timeout_ms = u64::from(self.interval_ms) * u64::from(self.max_missed);
+        for board in self.boards.iter_mut().filter(|board| !board.lost) {
+            if now_ms.saturating_sub(board.last_seen_ms) >= timeout_ms {
+                board.lost = true;
+                on_lost(board);
+            }
+        }
+    }
+
+    pub fn boards(&self) -> impl Iterator<Item = &BoardLiveness> {
+        self.boards.iter()
+    }
+
+    pub fn is_lost(&self, board: &str) -> Option<bool> {
+        self.boards
+            .iter()
+            .find(|known| known.board.as_str() == board)
+            .map(|known| known.lost)
+    }
+
+    fn find(&mut self, board: &str) -> Option<&mut BoardLiveness> {
+        self.boards
+            .iter_mut()
+            .find(|known| known.board.as_str() == board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Heartbeat, LivenessMonitor};
+
+    #[test]
+    fn lost_boards_stay_lost_until_they_beat() {
+        let mut monitor = LivenessMonitor::<2>::new(1000, 3);
+        monitor.watch("base-station", 0).unwrap();
+        assert_eq!(monitor.is_lost("base-station"), Some(false));
+        assert_eq!(monitor.is_lost("lim"), None);
+
+        let mut lost = 0;
+        monitor.check(2999, |_| lost += 1);
+        assert_eq!(lost, 0);
+        monitor.check(3000, |_| lost += 1);
+        monitor.check(9000, |_| lost += 1);
+        assert_eq!(lost, 1);
+        assert_eq!(monitor.is_lost("base-station"), Some(true));
+
+        let heartbeat = Heartbeat::new("base-station", 1);
+        assert!(monitor.record(&heartbeat.beat(9500), 9500));
+        assert_eq!(monitor.is_lost("base-station"), Some(false));
+        assert!(!monitor.record(&heartbeat.beat(10500), 10500));
+    }
+
+    #[test]
+    fn beats_report_the_tasks_that_checked_in() {
+        let heartbeat = Heartbeat::new("stm", 0b11);
+        heartbeat.check_in(0b01);
+        let beat = heartbeat.beat(0);
+        assert_eq!((beat.sequence, beat.healthy), (0, 0b01));
+        let beat = heartbeat.beat(1000);
+        assert_eq!((beat.sequence, beat.healthy), (1, 0));
+
+        let mut monitor = LivenessMonitor::<1>::new(1000, 3);
+        monitor.record(&beat, 1000);
+        assert_eq!(monitor.boards().next().unwrap().unhealthy_tasks(), 0b11);
+        // No room left to watch another board.
+        assert!(!monitor.record(&Heartbeat::new("lim", 1).beat(0), 1000));
+        assert_eq!(monitor.is_lost("lim"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "board id is too long")]
+    fn long_board_ids_are_rejected() {
+        Heartbeat::new("a-board-id-too-long", 1);
+    }
+}
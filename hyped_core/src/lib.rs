@@ -1,6 +1,21 @@
-#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+pub mod codec;
 pub mod format_string;
+pub mod heartbeat;
+pub mod log_sinks;
 pub mod logger;
 pub mod mqtt;
+pub mod mqtt_config;
+pub mod mqtt_connection;
+#[cfg(any(test, feature = "std"))]
+pub mod mqtt_loopback;
+pub mod mqtt_messages;
+pub mod mqtt_session;
+#[cfg(feature = "std")]
+pub mod mqtt_tcp;
 pub mod mqtt_topics;
+pub mod priority_channel;
+pub mod state_machine;
+#[cfg(any(test, feature = "std"))]
+mod std_logger;
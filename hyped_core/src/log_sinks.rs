@@ -0,0 +1,296 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex};
+use heapless::{HistoryBuffer, String};
+
+use crate::{
+    format_string::{show_string, TRUNCATION_MARKER},
+    logger::{LogBuffer, LogLevel, LogRecord, LogSink},
+    mqtt::{Error, MqttMessage},
+    priority_channel::{Priority, PriorityChannel},
+};
+
+/// Bytes cut from a log message per attempt to make its record fit in a payload.
+const TRUNCATION_STEP: usize = 32;
+
+/// Keeps the last `ENTRIES` messages in memory, each truncated to at most
+/// `LENGTH` bytes, so they can be read back after something went wrong.
+pub struct RingBufferSink<M: RawMutex, const ENTRIES: usize, const LENGTH: usize> {
+    entries: Mutex<M, RefCell<HistoryBuffer<(LogLevel, String<LENGTH>), ENTRIES>>>,
+}
+
+impl<M: RawMutex, const ENTRIES: usize, const LENGTH: usize> RingBufferSink<M, ENTRIES, LENGTH> {
+    pub const fn new() -> Self {
+        RingBufferSink {
+            entries: Mutex::new(RefCell::new(HistoryBuffer::new())),
+        }
+    }
+
+    /// Calls `f` with every stored message, oldest first.
+    pub fn for_each(&self, mut f: impl FnMut(LogLevel, &str)) {
+        self.entries.lock(|entries| {
+            for (level, message) in entries.borrow().oldest_ordered() {
+                f(*level, message);
+            }
+        })
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock(|entries| entries.borrow_mut().clear())
+    }
+}
+
+impl<M: RawMutex, const ENTRIES: usize, const LENGTH: usize> Default
+    for RingBufferSink<M, ENTRIES, LENGTH>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RawMutex + Sync, const ENTRIES: usize, const LENGTH: usize> LogSink
+    for RingBufferSink<M, ENTRIES, LENGTH>
+{
+    fn write(&self, record: &LogRecord) {
+        let entry = show_string(format_args!("{}", record.message.as_str()));
+        self.entries
+            .lock(|entries| entries.borrow_mut().write((record.level, entry)));
+    }
+}
+
+/// Publishes records on the Logs topic through the send queue. While the
+/// session is offline, or the queue is full, the most recent `BUFFERED`
+/// records are kept and sent in order once there is room again.
+pub struct MqttSink<'q, M: RawMutex, const HIGH: usize, const NORMAL: usize, const BUFFERED: usize>
+{
+    queue: &'q PriorityChannel<M, MqttMessage, HIGH, NORMAL>,
+    buffer: LogBuffer<M, BUFFERED>,
+    online: AtomicBool,
+    dropped: AtomicU32,
+}
+
+impl<'q, M: RawMutex, const HIGH: usize, const NORMAL: usize, const BUFFERED: usize>
+    MqttSink<'q, M, HIGH, NORMAL, BUFFERED>
+{
+    /// The sink starts offline, buffering until [`Self::set_online`] is called.
+    pub const fn new(queue: &'q PriorityChannel<M, MqttMessage, HIGH, NORMAL>) -> Self {
+        MqttSink {
+            queue,
+            buffer: LogBuffer::new(),
+            online: AtomicBool::new(false),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Called when the MQTT session connects or disconnects. Going online
+    /// moves as many buffered records into the queue as fit.
+    pub fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::Relaxed);
+        if online {
+            self.flush();
+        }
+    }
+
+    /// Moves buffered records into the queue, oldest first, until it is full.
+    /// Does nothing while offline.
+    pub fn flush(&self) {
+        if !self.online.load(Ordering::Relaxed) {
+            return;
+        }
+        self.buffer.drain_while(|record| match encode(record) {
+            Ok(message) => self.queue.try_send(message, Priority::Normal).is_ok(),
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        });
+    }
+
+    /// Number of records waiting to be sent.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of buffered records that were dropped to make room for newer ones.
+    pub fn overwritten(&self) -> u32 {
+        self.buffer.overwritten()
+    }
+
+    /// Number of records that could not be serialised, even with their
+    /// message cut short.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<M: RawMutex + Sync, const HIGH: usize, const NORMAL: usize, const BUFFERED: usize> LogSink
+    for MqttSink<'_, M, HIGH, NORMAL, BUFFERED>
+{
+    fn write(&self, record: &LogRecord) {
+        // Always go through the buffer so records keep their order.
+        self.buffer.push(record.clone());
+        self.flush();
+    }
+}
+
+/// Serialises `record`, cutting its message short until it fits in a payload.
+/// Escaped characters can make a message several times as long in JSON.
+fn encode(record: &LogRecord) -> Result<MqttMessage, Error> {
+    match MqttMessage::from_message(record) {
+        Err(Error::BufferOverflow) => {}
+        result => return result,
+    }
+    let text = record.message.as_str();
+    let mut shortened = record.clone();
+    let mut length = text.len();
+    while length > 0 {
+        length = length.saturating_sub(TRUNCATION_STEP);
+        while !text.is_char_boundary(length) {
+            length -= 1;
+        }
+        shortened.message.clear();
+        // Cannot fail, the message is at least a step shorter than before.
+        let _ = shortened.message.push_str(&text[..length]);
+        let _ = shortened.message.push_str(TRUNCATION_MARKER);
+        match MqttMessage::from_message(&shortened) {
+            Err(Error::BufferOverflow) => {}
+            result => return result,
+        }
+    }
+    Err(Error::BufferOverflow)
+}
+
+// The sink needs a Sync mutex, which only has a critical section on the host.
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use heapless::String;
+
+    use super::{MqttMessage, MqttSink, RingBufferSink};
+    use crate::codec::ContentType;
+    use crate::format_string::TRUNCATION_MARKER;
+    use crate::logger::{LogLevel, LogRecord, LogSink, MAX_LOG_LENGTH};
+    use crate::mqtt::MAX_PAYLOAD_SIZE;
+    use crate::mqtt_topics::MqttTopics;
+    use crate::priority_channel::PriorityChannel;
+
+    fn record(message: &str) -> LogRecord {
+        LogRecord {
+            level: LogLevel::Info,
+            board: String::try_from("stm").unwrap(),
+            source: String::new(),
+            timestamp_ms: 0,
+            sequence: 0,
+            message: String::try_from(message).unwrap(),
+        }
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_newest_entries_truncated() {
+        let sink = RingBufferSink::<CriticalSectionRawMutex, 2, 8>::new();
+        sink.write(&record("dropped"));
+        sink.write(&record("fits"));
+        sink.write(&record("ééééé"));
+
+        let mut entries = std::vec::Vec::new();
+        sink.for_each(|level, message| entries.push((level, message.to_string())));
+        let truncated = ["éé", TRUNCATION_MARKER].concat();
+        assert_eq!(
+            entries,
+            [
+                (LogLevel::Info, "fits".to_string()),
+                (LogLevel::Info, truncated)
+            ]
+        );
+
+        sink.clear();
+        sink.for_each(|_, _| panic!("not cleared"));
+    }
+
+    fn sent_messages<const HIGH: usize, const NORMAL: usize>(
+        queue: &PriorityChannel<CriticalSectionRawMutex, MqttMessage, HIGH, NORMAL>,
+    ) -> Vec<String<MAX_LOG_LENGTH>> {
+        let mut messages = Vec::new();
+        while let Some(message) = queue.try_receive() {
+            assert_eq!(message.kind, MqttTopics::Logs);
+            let record: LogRecord = ContentType::Json.decode(&message.payload).unwrap();
+            messages.push(record.message);
+        }
+        messages
+    }
+
+    #[test]
+    fn records_are_buffered_until_online() {
+        let queue = PriorityChannel::<CriticalSectionRawMutex, _, 4, 4>::new();
+        let sink = MqttSink::<_, 4, 4, 2>::new(&queue);
+        for message in ["first", "second", "third"] {
+            sink.write(&record(message));
+        }
+        assert!(queue.is_empty());
+        assert_eq!(sink.buffered(), 2);
+        assert_eq!(sink.overwritten(), 1);
+
+        sink.set_online(true);
+        assert_eq!(sink.buffered(), 0);
+        assert_eq!(sent_messages(&queue), ["second", "third"]);
+
+        sink.set_online(false);
+        sink.write(&record("offline"));
+        assert!(queue.is_empty());
+        assert_eq!(sink.buffered(), 1);
+    }
+
+    #[test]
+    fn records_wait_for_room_in_the_normal_lane() {
+        // The high lane has room for everything, but is never used.
+        let queue = PriorityChannel::<CriticalSectionRawMutex, _, 4, 1>::new();
+        let sink = MqttSink::<_, 4, 1, 4>::new(&queue);
+        sink.set_online(true);
+        for message in ["first", "second", "third"] {
+            sink.write(&record(message));
+        }
+        assert_eq!(queue.len(), 1);
+        assert_eq!(sink.buffered(), 2);
+
+        assert_eq!(sent_messages(&queue), ["first"]);
+        sink.flush();
+        assert_eq!(sent_messages(&queue), ["second"]);
+        sink.write(&record("fourth"));
+        assert_eq!(sent_messages(&queue), ["third"]);
+        sink.flush();
+        assert_eq!(sent_messages(&queue), ["fourth"]);
+        assert_eq!(sink.buffered(), 0);
+        assert_eq!(sink.overwritten(), 0);
+    }
+
+    #[test]
+    fn long_records_are_cut_to_fit_a_payload() {
+        let queue = PriorityChannel::<CriticalSectionRawMutex, _, 1, 1>::new();
+        let sink = MqttSink::<_, 1, 1, 1>::new(&queue);
+        sink.set_online(true);
+        // Every quote and newline is escaped, doubling the message in JSON.
+        let text = "\"\n".repeat(MAX_LOG_LENGTH / 2);
+        let long = LogRecord {
+            level: LogLevel::Warn,
+            source: String::try_from("stm::sensors").unwrap(),
+            timestamp_ms: 1234,
+            sequence: 56,
+            ..record(&text)
+        };
+        sink.write(&long);
+
+        let message = queue.try_receive().unwrap();
+        assert!(message.payload.len() <= MAX_PAYLOAD_SIZE);
+        let sent: LogRecord = ContentType::Json.decode(&message.payload).unwrap();
+        let kept = sent.message.strip_suffix(TRUNCATION_MARKER).unwrap();
+        assert!(!kept.is_empty() && kept.len() < text.len(), "{kept:?}");
+        assert!(text.starts_with(kept));
+        let expected = LogRecord {
+            message: sent.message.clone(),
+            ..long
+        };
+        assert_eq!(sent, expected);
+        assert_eq!(sink.dropped(), 0);
+    }
+}
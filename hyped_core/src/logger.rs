@@ -1,4 +1,22 @@
-#[derive(PartialEq, PartialOrd)]
+use core::cell::RefCell;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex};
+use heapless::{Deque, String};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::ContentType, format_string::show_string, mqtt::Error, mqtt_messages::LogLevelMessage,
+    mqtt_topics::MAX_BOARD_LENGTH,
+};
+
+/// Longest message kept in a [`LogRecord`], in bytes. Longer messages are truncated.
+pub const MAX_LOG_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format, Serialize, Deserialize)]
 pub enum LogLevel {
     Debug = 0,
     Info = 1,
@@ -6,7 +24,766 @@ pub enum LogLevel {
     Error = 3,
 }
 
+impl LogLevel {
+    fn from_u8(level: u8) -> Option<LogLevel> {
+        match level {
+            0 => Some(LogLevel::Debug),
+            1 => Some(LogLevel::Info),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Error),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// One logged message, as published on the Logs topic.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub board: String<MAX_BOARD_LENGTH>,
+    /// Module or task that logged the message.
+    pub source: String<48>,
+    /// Milliseconds since the board booted.
+    pub timestamp_ms: u64,
+    /// Counts up with every record a board creates, so gaps show records that
+    /// were filtered out or lost.
+    pub sequence: u32,
+    pub message: String<MAX_LOG_LENGTH>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
 pub enum LogTarget {
     Console,
     Mqtt,
+    Memory,
+}
+
+impl LogTarget {
+    pub const ALL: [LogTarget; 3] = [LogTarget::Console, LogTarget::Mqtt, LogTarget::Memory];
+}
+
+const TARGETS: usize = LogTarget::ALL.len();
+
+impl FromStr for LogTarget {
+    type Err = ();
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target {
+            "console" => Ok(LogTarget::Console),
+            "mqtt" => Ok(LogTarget::Mqtt),
+            "memory" => Ok(LogTarget::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Stored in place of a level for targets that are switched off.
+const LEVEL_OFF: u8 = u8::MAX;
+
+const LEVELS: usize = 4;
+
+/// While one record keeps repeating, how often the number of repeats is reported.
+const REPEAT_REPORT_MS: u32 = 1000;
+
+/// At most `max_records` records of one level are passed on per `window_ms`.
+/// The number of records dropped is reported when the next window starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RateLimit {
+    pub max_records: u32,
+    pub window_ms: u32,
+}
+
+/// Somewhere log messages end up. Sinks are shared between tasks, so they
+/// must not block and have to handle their own synchronisation.
+pub trait LogSink: Sync {
+    fn write(&self, record: &LogRecord);
+}
+
+/// Sends each message to the sink of every target whose minimum level it
+/// meets. Levels can be changed at runtime from any task.
+pub struct Logger<'a> {
+    board: &'a str,
+    /// Milliseconds since boot.
+    clock: fn() -> u64,
+    sequence: AtomicU32,
+    levels: [AtomicU8; TARGETS],
+    sinks: [Option<&'a dyn LogSink>; TARGETS],
+    deduplicate: [bool; LEVELS],
+    /// Hash of the last record that was not a repeat, and how often it was
+    /// repeated since it or the previous report of its repeats.
+    last_hash: AtomicU32,
+    last_level: AtomicU8,
+    repeats: AtomicU32,
+    repeats_reported_ms: AtomicU32,
+    rate_limits: [Option<RateLimit>; LEVELS],
+    window_start: [AtomicU32; LEVELS],
+    window_count: [AtomicU32; LEVELS],
+    rate_limited: [AtomicU32; LEVELS],
+}
+
+impl<'a> Logger<'a> {
+    /// A logger without sinks, which discards everything.
+    pub const fn new(board: &'a str, clock: fn() -> u64) -> Self {
+        Logger {
+            board,
+            clock,
+            sequence: AtomicU32::new(0),
+            levels: [const { AtomicU8::new(LEVEL_OFF) }; TARGETS],
+            sinks: [None; TARGETS],
+            deduplicate: [false; LEVELS],
+            last_hash: AtomicU32::new(0),
+            last_level: AtomicU8::new(0),
+            repeats: AtomicU32::new(0),
+            repeats_reported_ms: AtomicU32::new(0),
+            rate_limits: [None; LEVELS],
+            window_start: [const { AtomicU32::new(0) }; LEVELS],
+            window_count: [const { AtomicU32::new(0) }; LEVELS],
+            rate_limited: [const { AtomicU32::new(0) }; LEVELS],
+        }
+    }
+
+    /// Writes messages of at least `level` for `target` to `sink`.
+    pub const fn with_sink(
+        mut self,
+        target: LogTarget,
+        sink: &'a dyn LogSink,
+        level: LogLevel,
+    ) -> Self {
+        self.levels[target as usize] = AtomicU8::new(level as u8);
+        self.sinks[target as usize] = Some(sink);
+        self
+    }
+
+    /// Collapses consecutive identical records of `level` into one record
+    /// followed by "last message repeated N times", which is reported when a
+    /// different record arrives, by [`Self::flush`], and every
+    /// `REPEAT_REPORT_MS` while the record keeps repeating.
+    pub const fn with_deduplication(mut self, level: LogLevel) -> Self {
+        self.deduplicate[level as usize] = true;
+        self
+    }
+
+    pub const fn with_rate_limit(mut self, level: LogLevel, limit: RateLimit) -> Self {
+        self.rate_limits[level as usize] = Some(limit);
+        self
+    }
+
+    /// The minimum level for `target`, `None` if it is switched off.
+    pub fn level(&self, target: LogTarget) -> Option<LogLevel> {
+        LogLevel::from_u8(self.levels[target as usize].load(Ordering::Relaxed))
+    }
+
+    /// Changes the minimum level for `target`, or switches it off with `None`.
+    pub fn set_level(&self, target: LogTarget, level: Option<LogLevel>) {
+        let level = level.map_or(LEVEL_OFF, |level| level as u8);
+        self.levels[target as usize].store(level, Ordering::Relaxed);
+    }
+
+    /// Applies a level change sent over MQTT. Returns `false` if the message
+    /// is addressed to another board.
+    pub fn apply(&self, message: &LogLevelMessage) -> bool {
+        if message
+            .board
+            .as_ref()
+            .is_some_and(|board| board.as_str() != self.board)
+        {
+            return false;
+        }
+        self.set_level(message.target, message.level);
+        true
+    }
+
+    /// Decodes a [`LogLevelMessage`] received on the LogLevel topic, in the
+    /// encoding named by the topic, and applies it.
+    pub fn apply_payload(&self, content_type: ContentType, payload: &[u8]) -> Result<bool, Error> {
+        content_type
+            .decode::<LogLevelMessage>(payload)
+            .map(|message| self.apply(&message))
+    }
+
+    fn accepts(&self, target: LogTarget, level: LogLevel) -> bool {
+        self.sinks[target as usize].is_some()
+            && self.level(target).is_some_and(|minimum| level >= minimum)
+    }
+
+    /// Whether any sink would receive a message of `level`.
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        LogTarget::ALL
+            .iter()
+            .any(|&target| self.accepts(target, level))
+    }
+
+    /// Logs `message`, truncated like the `hlog_*` macros if it is longer
+    /// than [`MAX_LOG_LENGTH`].
+    pub fn log(&self, level: LogLevel, source: &str, message: &str) {
+        if let Some(mut record) = self.record(level, source) {
+            record.message = show_string(format_args!("{}", message));
+            self.submit(record);
+        }
+    }
+
+    /// Formats the message, but only if a sink will receive it. Messages
+    /// longer than [`MAX_LOG_LENGTH`] are truncated. Used by the `hlog_*` macros.
+    pub fn log_fmt(&self, level: LogLevel, source: &str, args: fmt::Arguments) {
+        if let Some(mut record) = self.record(level, source) {
+            record.message = show_string(args);
+            self.submit(record);
+        }
+    }
+
+    fn record(&self, level: LogLevel, source: &str) -> Option<LogRecord> {
+        if !self.enabled(level) {
+            return None;
+        }
+        Some(LogRecord {
+            level,
+            board: show_string(format_args!("{}", self.board)),
+            source: show_string(format_args!("{}", source)),
+            timestamp_ms: (self.clock)(),
+            sequence: 0,
+            message: String::new(),
+        })
+    }
+
+    fn submit(&self, mut record: LogRecord) {
+        if self.is_repeat(&record) || self.is_rate_limited(&record) {
+            return;
+        }
+        self.dispatch(&mut record);
+    }
+
+    fn is_repeat(&self, record: &LogRecord) -> bool {
+        let hash = hash_record(record);
+        // Only the low 32 bits are kept, which is enough to measure intervals.
+        let now = record.timestamp_ms as u32;
+        // Records of any level end a run of repeats, so that a record logged
+        // again after another one is not taken as a repeat.
+        let last_hash = self.last_hash.swap(hash, Ordering::Relaxed);
+        if self.deduplicate[record.level as usize] && last_hash == hash {
+            self.repeats.fetch_add(1, Ordering::Relaxed);
+            let reported = self.repeats_reported_ms.load(Ordering::Relaxed);
+            if now.wrapping_sub(reported) >= REPEAT_REPORT_MS {
+                self.repeats_reported_ms.store(now, Ordering::Relaxed);
+                self.report_repeats(record.level);
+            }
+            return true;
+        }
+        self.repeats_reported_ms.store(now, Ordering::Relaxed);
+        let last_level = self.last_level.swap(record.level as u8, Ordering::Relaxed);
+        if let Some(level) = LogLevel::from_u8(last_level) {
+            self.report_repeats(level);
+        }
+        false
+    }
+
+    /// Reports repeats of the last deduplicated record that have not been
+    /// reported yet, e.g. from a periodic task so that the count is not held
+    /// back until the next different record.
+    pub fn flush(&self) {
+        if let Some(level) = LogLevel::from_u8(self.last_level.load(Ordering::Relaxed)) {
+            self.report_repeats(level);
+        }
+    }
+
+    fn report_repeats(&self, level: LogLevel) {
+        let repeats = self.repeats.swap(0, Ordering::Relaxed);
+        if repeats > 0 {
+            self.summary(
+                level,
+                format_args!(
+                    "last message repeated {} {}",
+                    repeats,
+                    if repeats == 1 { "time" } else { "times" }
+                ),
+            );
+        }
+    }
+
+    fn is_rate_limited(&self, record: &LogRecord) -> bool {
+        let level = record.level as usize;
+        let Some(limit) = self.rate_limits[level] else {
+            return false;
+        };
+        // Only the low 32 bits are kept, which is enough to compare windows.
+        let now = record.timestamp_ms as u32;
+        if now.wrapping_sub(self.window_start[level].load(Ordering::Relaxed)) >= limit.window_ms {
+            self.window_start[level].store(now, Ordering::Relaxed);
+            self.window_count[level].store(0, Ordering::Relaxed);
+            let dropped = self.rate_limited[level].swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                self.summary(
+                    record.level,
+                    format_args!("{} messages dropped by the rate limit", dropped),
+                );
+            }
+        }
+        if self.window_count[level].fetch_add(1, Ordering::Relaxed) < limit.max_records {
+            return false;
+        }
+        self.rate_limited[level].fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// A record about suppressed records, which is not suppressed itself.
+    fn summary(&self, level: LogLevel, args: fmt::Arguments) {
+        if let Some(mut record) = self.record(level, module_path!()) {
+            record.message = show_string(args);
+            self.dispatch(&mut record);
+        }
+    }
+
+    fn dispatch(&self, record: &mut LogRecord) {
+        record.sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        for target in LogTarget::ALL {
+            if self.accepts(target, record.level) {
+                if let Some(sink) = self.sinks[target as usize] {
+                    sink.write(record);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the most recent `N` records, e.g. while the broker cannot be reached,
+/// so they can be sent in order later.
+pub struct LogBuffer<M: RawMutex, const N: usize> {
+    records: Mutex<M, RefCell<Deque<LogRecord, N>>>,
+    overwritten: AtomicU32,
+}
+
+impl<M: RawMutex, const N: usize> LogBuffer<M, N> {
+    pub const fn new() -> Self {
+        LogBuffer {
+            records: Mutex::new(RefCell::new(Deque::new())),
+            overwritten: AtomicU32::new(0),
+        }
+    }
+
+    /// Appends `record`, dropping the oldest record if the buffer is full.
+    pub fn push(&self, record: LogRecord) {
+        self.records.lock(|records| {
+            let mut records = records.borrow_mut();
+            if records.is_full() {
+                records.pop_front();
+                self.overwritten.fetch_add(1, Ordering::Relaxed);
+            }
+            // Cannot fail, there is space after the pop above.
+            let _ = records.push_back(record);
+        })
+    }
+
+    /// Passes records to `send`, oldest first, and removes them until `send`
+    /// returns `false`.
+    pub fn drain_while(&self, mut send: impl FnMut(&LogRecord) -> bool) {
+        self.records.lock(|records| {
+            let mut records = records.borrow_mut();
+            while records.front().is_some_and(&mut send) {
+                records.pop_front();
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock(|records| records.borrow().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of records dropped because the buffer was full.
+    pub fn overwritten(&self) -> u32 {
+        self.overwritten.load(Ordering::Relaxed)
+    }
+}
+
+impl<M: RawMutex, const N: usize> Default for LogBuffer<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a over the parts of a record that make it a repeat.
+fn hash_record(record: &LogRecord) -> u32 {
+    let mut hash = 0x811c_9dc5_u32;
+    let parts = [
+        &[record.level as u8][..],
+        record.source.as_bytes(),
+        &[0],
+        record.message.as_bytes(),
+    ];
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        hash = (hash ^ *byte as u32).wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Logs to the defmt console.
+pub struct DefmtSink;
+
+impl LogSink for DefmtSink {
+    fn write(&self, record: &LogRecord) {
+        let source = record.source.as_str();
+        let message = record.message.as_str();
+        match record.level {
+            LogLevel::Debug => defmt::debug!("{}: {}", source, message),
+            LogLevel::Info => defmt::info!("{}: {}", source, message),
+            LogLevel::Warn => defmt::warn!("{}: {}", source, message),
+            LogLevel::Error => defmt::error!("{}: {}", source, message),
+        }
+    }
+}
+
+/// Logs to standard output, for tools running on the host.
+#[cfg(feature = "std")]
+pub struct StdoutSink;
+
+#[cfg(feature = "std")]
+impl LogSink for StdoutSink {
+    fn write(&self, record: &LogRecord) {
+        println!(
+            "[{:?}] {}: {}",
+            record.level,
+            record.source.as_str(),
+            record.message.as_str()
+        );
+    }
+}
+
+/// Logs a message formatted without heap allocation, e.g.
+/// `hlog!(LOGGER, LogLevel::Info, "speed {}", speed)`.
+#[macro_export]
+macro_rules! hlog {
+    ($logger:expr, $level:expr, $($arg:tt)+) => {
+        $logger.log_fmt($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! hlog_debug {
+    ($logger:expr, $($arg:tt)+) => {
+        $crate::hlog!($logger, $crate::logger::LogLevel::Debug, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! hlog_info {
+    ($logger:expr, $($arg:tt)+) => {
+        $crate::hlog!($logger, $crate::logger::LogLevel::Info, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! hlog_warn {
+    ($logger:expr, $($arg:tt)+) => {
+        $crate::hlog!($logger, $crate::logger::LogLevel::Warn, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! hlog_error {
+    ($logger:expr, $($arg:tt)+) => {
+        $crate::hlog!($logger, $crate::logger::LogLevel::Error, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::{
+        LogBuffer, LogLevel, LogRecord, LogSink, LogTarget, Logger, RateLimit, MAX_LOG_LENGTH,
+    };
+    use crate::codec::ContentType;
+    use crate::format_string::TRUNCATION_MARKER;
+    use crate::mqtt::{Error, MAX_PAYLOAD_SIZE};
+    use crate::mqtt_messages::LogLevelMessage;
+
+    thread_local! {
+        static NOW_MS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn now_ms() -> u64 {
+        NOW_MS.with(Cell::get)
+    }
+
+    fn set_now_ms(now_ms: u64) {
+        NOW_MS.with(|now| now.set(now_ms));
+    }
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<LogRecord>>);
+
+    impl LogSink for Collect {
+        fn write(&self, record: &LogRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    impl Collect {
+        fn messages(&self) -> Vec<String> {
+            let records = self.0.lock().unwrap();
+            records.iter().map(|r| r.message.to_string()).collect()
+        }
+    }
+
+    fn logger(sink: &Collect) -> Logger<'_> {
+        set_now_ms(0);
+        Logger::new("stm", now_ms)
+            .with_sink(LogTarget::Memory, sink, LogLevel::Debug)
+            .with_deduplication(LogLevel::Error)
+            .with_rate_limit(
+                LogLevel::Info,
+                RateLimit {
+                    max_records: 3,
+                    window_ms: 1000,
+                },
+            )
+    }
+
+    #[test]
+    fn repeats_are_collapsed() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        for now in 0..5 {
+            set_now_ms(now);
+            logger.log(LogLevel::Error, "brakes", "pressure low");
+        }
+        logger.log(LogLevel::Error, "brakes", "pressure ok");
+        logger.log(LogLevel::Error, "brakes", "pressure ok");
+        assert_eq!(
+            sink.messages(),
+            [
+                "pressure low",
+                "last message repeated 4 times",
+                "pressure ok"
+            ]
+        );
+
+        // Only deduplicated levels are collapsed.
+        logger.log(LogLevel::Warn, "brakes", "check");
+        logger.log(LogLevel::Warn, "brakes", "check");
+        assert_eq!(
+            sink.messages()[3..],
+            ["last message repeated 1 time", "check", "check"]
+        );
+    }
+
+    #[test]
+    fn repeats_are_only_of_the_previous_record() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        logger.log(LogLevel::Error, "brakes", "pressure low");
+        logger.log(LogLevel::Warn, "brakes", "check");
+        logger.log(LogLevel::Error, "brakes", "pressure low");
+        logger.log(LogLevel::Error, "brakes", "pressure ok");
+        logger.log(LogLevel::Error, "brakes", "pressure low");
+        assert_eq!(
+            sink.messages(),
+            [
+                "pressure low",
+                "check",
+                "pressure low",
+                "pressure ok",
+                "pressure low"
+            ]
+        );
+    }
+
+    #[test]
+    fn endless_repeats_are_reported_periodically() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        for now in (0..=2500).step_by(100) {
+            set_now_ms(now);
+            logger.log(LogLevel::Error, "brakes", "pressure low");
+        }
+        assert_eq!(
+            sink.messages(),
+            [
+                "pressure low",
+                "last message repeated 10 times",
+                "last message repeated 10 times"
+            ]
+        );
+
+        logger.flush();
+        assert_eq!(sink.messages()[3], "last message repeated 5 times");
+        logger.flush();
+        assert_eq!(sink.messages().len(), 4);
+
+        let records = sink.0.lock().unwrap();
+        assert!(records.iter().all(|record| record.level == LogLevel::Error));
+        assert_eq!(records[1].timestamp_ms, 1000);
+        assert_eq!(records[2].timestamp_ms, 2000);
+    }
+
+    #[test]
+    fn rate_limit_drops_and_reports() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        for sample in 0..5 {
+            logger.log_fmt(LogLevel::Info, "nav", format_args!("sample {}", sample));
+        }
+        // Other levels have their own limits.
+        logger.log(LogLevel::Debug, "nav", "debug");
+        set_now_ms(999);
+        logger.log(LogLevel::Info, "nav", "still limited");
+        set_now_ms(1000);
+        logger.log(LogLevel::Info, "nav", "next window");
+        assert_eq!(
+            sink.messages(),
+            [
+                "sample 0",
+                "sample 1",
+                "sample 2",
+                "debug",
+                "3 messages dropped by the rate limit",
+                "next window"
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence_counts_every_record() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        logger.log(LogLevel::Error, "a", "one");
+        logger.log(LogLevel::Error, "a", "one");
+        logger.log(LogLevel::Error, "a", "two");
+        let records = sink.0.lock().unwrap();
+        let sequences: Vec<u32> = records.iter().map(|record| record.sequence).collect();
+        assert_eq!(sequences, [0, 1, 2]);
+        assert_eq!(records[0].board, "stm");
+        assert_eq!(records[0].source, "a");
+    }
+
+    #[test]
+    fn long_messages_are_truncated_alike() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        let message = "é".repeat(MAX_LOG_LENGTH);
+        logger.log(LogLevel::Warn, "nav", &message);
+        logger.log_fmt(LogLevel::Warn, "nav", format_args!("{}", message));
+        let messages = sink.messages();
+        assert_eq!(messages[0], messages[1]);
+        assert!(messages[0].ends_with(TRUNCATION_MARKER));
+        assert!(messages[0].len() <= MAX_LOG_LENGTH);
+    }
+
+    #[test]
+    fn levels_are_changed_per_board() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        let mut message = LogLevelMessage {
+            board: Some("lim".try_into().unwrap()),
+            target: LogTarget::Memory,
+            level: Some(LogLevel::Warn),
+        };
+        assert!(!logger.apply(&message));
+        assert_eq!(logger.level(LogTarget::Memory), Some(LogLevel::Debug));
+
+        message.board = Some("stm".try_into().unwrap());
+        assert!(logger.apply(&message));
+        assert!(!logger.enabled(LogLevel::Info));
+        logger.log(LogLevel::Info, "nav", "hidden");
+        logger.log(LogLevel::Warn, "nav", "shown");
+
+        message.board = None;
+        message.level = None;
+        assert!(logger.apply(&message));
+        logger.log(LogLevel::Error, "nav", "off");
+        assert_eq!(sink.messages(), ["shown"]);
+    }
+
+    #[test]
+    fn levels_are_decoded_in_the_topic_encoding() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        let message = LogLevelMessage {
+            board: None,
+            target: LogTarget::Memory,
+            level: Some(LogLevel::Error),
+        };
+        for content_type in ContentType::ALL {
+            logger.set_level(LogTarget::Memory, Some(LogLevel::Debug));
+            let mut buffer = [0; MAX_PAYLOAD_SIZE];
+            let length = content_type.encode(&message, &mut buffer).unwrap();
+            assert_eq!(
+                logger.apply_payload(content_type, &buffer[..length]),
+                Ok(true)
+            );
+            assert_eq!(logger.level(LogTarget::Memory), Some(LogLevel::Error));
+        }
+        assert_eq!(
+            logger.apply_payload(ContentType::Postcard, b"{}"),
+            Err(Error::Decode)
+        );
+    }
+
+    fn drain_all<const N: usize>(buffer: &LogBuffer<NoopRawMutex, N>) -> Vec<String> {
+        let mut messages = Vec::new();
+        buffer.drain_while(|record| {
+            messages.push(record.message.to_string());
+            true
+        });
+        messages
+    }
+
+    #[test]
+    fn full_log_buffer_overwrites_the_oldest_records() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        for message in ["first", "second", "third", "fourth"] {
+            logger.log(LogLevel::Warn, "brakes", message);
+        }
+        let buffer = LogBuffer::<NoopRawMutex, 2>::new();
+        for record in sink.0.lock().unwrap().iter() {
+            buffer.push(record.clone());
+        }
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.overwritten(), 2);
+        assert_eq!(drain_all(&buffer), ["third", "fourth"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn log_buffer_drains_until_refused() {
+        let sink = Collect::default();
+        let logger = logger(&sink);
+        for message in ["first", "second", "third"] {
+            logger.log(LogLevel::Warn, "brakes", message);
+        }
+        let buffer = LogBuffer::<NoopRawMutex, 4>::new();
+        for record in sink.0.lock().unwrap().iter() {
+            buffer.push(record.clone());
+        }
+        let mut sent = Vec::new();
+        buffer.drain_while(|record| {
+            sent.push(record.message.to_string());
+            sent.len() < 2
+        });
+        // The refused record stays at the front.
+        assert_eq!(sent, ["first", "second"]);
+        assert_eq!(drain_all(&buffer), ["second", "third"]);
+        assert_eq!(buffer.overwritten(), 0);
+    }
 }
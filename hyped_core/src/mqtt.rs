@@ -1,23 +1,121 @@
 use defmt::*;
-#[cfg(not(feature = "std"))]
-use heapless::String;
+use heapless::{String, Vec};
 use rust_mqtt::{
-    client::{client::MqttClient, client_config::ClientConfig},
-    packet::v5::reason_codes::ReasonCode,
-    utils::rng_generator::CountingRng,
+    client::client::MqttClient,
+    packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
 };
 use serde::{Deserialize, Serialize};
 
-#[cfg(not(feature = "std"))]
+use crate::codec::{Codec, ContentType, Json};
+use crate::mqtt_messages::TopicMessage;
+use crate::mqtt_topics::{MqttTopics, TopicNamespace, MAX_TOPIC_LENGTH};
+
+/// Largest payload a typed message is serialised into.
+pub const MAX_PAYLOAD_SIZE: usize = 512;
+
+/// Largest packet a client sends or accepts: a PUBLISH with a topic and
+/// payload at their limits, plus room for its header and properties.
+pub const MAX_PACKET_SIZE: u32 = (MAX_TOPIC_LENGTH + MAX_PAYLOAD_SIZE + 64) as u32;
+
+/// A message waiting to be published. The topic is only formatted when it is
+/// sent, in the [`TopicNamespace`] of the client sending it.
 pub struct MqttMessage {
-    pub topic: String<48>,
-    pub payload: String<512>,
+    pub kind: MqttTopics,
+    pub content_type: ContentType,
+    pub payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+    /// When the message was produced, in milliseconds of the session's clock.
+    /// Rate limits are applied to this time, so that messages held up in the
+    /// queue are not dropped. Unset means when it is published.
+    pub created_ms: Option<u64>,
 }
 
-#[cfg(feature = "std")]
-pub struct MqttMessage {
-    pub topic: String,
-    pub payload: String,
+impl MqttMessage {
+    /// Serialises `message` as JSON and addresses it to the topic bound to its type.
+    pub fn from_message<M: TopicMessage>(message: &M) -> Result<Self, Error> {
+        Self::from_message_with::<Json, M>(message)
+    }
+
+    /// Like [`Self::from_message`], but encodes `message` with `C`, on the
+    /// topic suffixed with its content type.
+    pub fn from_message_with<C: Codec, M: TopicMessage>(message: &M) -> Result<Self, Error> {
+        let mut buffer = [0; MAX_PAYLOAD_SIZE];
+        let length = C::encode(message, &mut buffer)?;
+        Self::encoded(M::TOPIC, C::CONTENT_TYPE, &buffer[..length])
+    }
+
+    /// Stamps the time the message was produced, see [`Self::created_ms`].
+    pub fn created_at(mut self, now_ms: u64) -> Self {
+        self.created_ms = Some(now_ms);
+        self
+    }
+
+    /// The full topic when published from `namespace`.
+    pub fn topic(&self, namespace: &TopicNamespace) -> Result<String<MAX_TOPIC_LENGTH>, Error> {
+        namespace.topic(self.kind, self.content_type)
+    }
+
+    /// The payload as text, failing if it is not valid UTF-8.
+    pub fn text(&self) -> Result<&str, Error> {
+        payload_text(&self.payload)
+    }
+
+    fn encoded(kind: MqttTopics, content_type: ContentType, payload: &[u8]) -> Result<Self, Error> {
+        Ok(MqttMessage {
+            kind,
+            content_type,
+            payload: Vec::from_slice(payload).map_err(|_| Error::BufferOverflow)?,
+            created_ms: None,
+        })
+    }
+}
+
+/// Views a received payload as text, failing if it is not valid UTF-8.
+pub fn payload_text(payload: &[u8]) -> Result<&str, Error> {
+    core::str::from_utf8(payload).map_err(|_| Error::Decode)
+}
+
+/// Errors returned by [`HypedMqttClient`], grouped by what the caller can do
+/// about them.
+#[derive(Debug, PartialEq, Format)]
+pub enum Error {
+    /// The TCP connection or MQTT session is gone and has to be re-established.
+    Network,
+    /// The broker refused the request.
+    Rejected(ReasonCode),
+    /// A payload could not be decoded.
+    Decode,
+    /// A message or topic did not fit into its buffer.
+    BufferOverflow,
+    /// The broker did not answer in time.
+    Timeout,
+}
+
+impl Error {
+    /// Whether the session is unusable and the caller should reconnect.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            Error::Network | Error::Timeout => true,
+            Error::Rejected(reason) => matches!(
+                reason,
+                ReasonCode::SessionTakeOver
+                    | ReasonCode::ServerShuttingDown
+                    | ReasonCode::ServerUnavailable
+                    | ReasonCode::MaximumConnectTime
+            ),
+            Error::Decode | Error::BufferOverflow => false,
+        }
+    }
+}
+
+impl From<ReasonCode> for Error {
+    fn from(reason: ReasonCode) -> Self {
+        match reason {
+            ReasonCode::NetworkError => Error::Network,
+            ReasonCode::KeepAliveTimeout => Error::Timeout,
+            ReasonCode::BuffError | ReasonCode::PacketTooLarge => Error::BufferOverflow,
+            _ => Error::Rejected(reason),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -32,91 +130,116 @@ pub struct HypedMqttClient<
     R: rand_core::RngCore,
 > {
     pub client: MqttClient<'a, T, 5, R>,
-}
-
-pub fn initialise_mqtt_config() -> ClientConfig<'static, 5, CountingRng> {
-    let mut config = ClientConfig::new(
-        rust_mqtt::client::client_config::MqttVersion::MQTTv5,
-        CountingRng(20000),
-    );
-    config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-    config.add_client_id("stm-client");
-    config.max_packet_size = 100;
-
-    config
+    /// Where the typed messages are published.
+    pub namespace: TopicNamespace<'a>,
 }
 
 // Implement send_message for HypedMqttClient
 impl<'a, T: embedded_io_async::Read + embedded_io_async::Write, R: rand_core::RngCore>
     HypedMqttClient<'a, T, R>
 {
-    pub async fn connect_to_broker(&mut self) {
-        match self.client.connect_to_broker().await {
-            Ok(()) => {}
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    info!("MQTT Network Error");
-                }
-                _ => {
-                    warn!("Other MQTT Error: {:?}", mqtt_error);
-                }
-            },
-        }
+    pub async fn connect_to_broker(&mut self) -> Result<(), Error> {
+        self.client.connect_to_broker().await.map_err(log_error)
     }
 
-    pub async fn send_message(&mut self, topic: &str, message: &[u8], retain: bool) {
-        match self
-            .client
-            .send_message(
-                topic,
-                message,
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                retain,
-            )
+    pub async fn send_message(
+        &mut self,
+        topic: &str,
+        message: &[u8],
+        qos: QualityOfService,
+        retain: bool,
+    ) -> Result<(), Error> {
+        self.client
+            .send_message(topic, message, qos, retain)
             .await
-        {
-            Ok(()) => {}
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    info!("MQTT Network Error");
-                }
-                _ => {
-                    warn!("Other MQTT Error: {:?}", mqtt_error);
-                }
-            },
-        }
+            .map_err(log_error)
     }
 
-    pub async fn subscribe(&mut self, topic: &str) {
-        match self.client.subscribe_to_topic(topic).await {
-            Ok(()) => {}
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    info!("MQTT Network Error");
-                }
-                _ => {
-                    warn!("Other MQTT Error: {:?}", mqtt_error);
-                }
-            },
-        }
+    /// Publishes a queued message with the QoS and retain flag of its topic's
+    /// [`crate::mqtt_topics::TopicPolicy`].
+    pub async fn publish_message(&mut self, message: &MqttMessage) -> Result<(), Error> {
+        let topic = message.topic(&self.namespace)?;
+        let policy = message.kind.policy();
+        self.send_message(topic.as_str(), &message.payload, policy.qos, policy.retain)
+            .await
+    }
+
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.client
+            .subscribe_to_topic(topic)
+            .await
+            .map_err(log_error)
+    }
+
+    /// Serialises `message` as JSON and publishes it on the topic bound to its
+    /// type, according to the topic's [`crate::mqtt_topics::TopicPolicy`].
+    pub async fn publish<M: TopicMessage>(&mut self, message: &M) -> Result<(), Error> {
+        self.publish_with::<Json, M>(message).await
+    }
+
+    /// Like [`Self::publish`], but encodes `message` with `C`, on the topic
+    /// suffixed with its content type.
+    pub async fn publish_with<C: Codec, M: TopicMessage>(
+        &mut self,
+        message: &M,
+    ) -> Result<(), Error> {
+        let mut buffer = [0; MAX_PAYLOAD_SIZE];
+        let length = C::encode(message, &mut buffer)?;
+        let topic = self.namespace.topic(M::TOPIC, C::CONTENT_TYPE)?;
+        let policy = M::TOPIC.policy();
+        self.send_message(topic.as_str(), &buffer[..length], policy.qos, policy.retain)
+            .await
     }
 
-    pub async fn receive_message(&mut self) -> Result<(&str, &str), ReasonCode> {
-        match self.client.receive_message().await {
-            Ok((topic, payload)) => {
-                let payload_str = core::str::from_utf8(payload).unwrap();
-                Ok((topic, payload_str))
-            }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    info!("MQTT Network Error");
-                    return Err(ReasonCode::NetworkError);
-                }
-                _ => {
-                    warn!("Other MQTT Error: {:?}", mqtt_error);
-                    return Err(mqtt_error);
-                }
-            },
+    /// Waits for the next message and deserialises it in whichever encoding it
+    /// was published. A message on any other topic than the one bound to `M`
+    /// is reported as [`Error::Decode`].
+    pub async fn receive<M: TopicMessage>(&mut self) -> Result<M, Error> {
+        let (topic, payload) = self.client.receive_message().await.map_err(log_error)?;
+        let Some(parsed) = MqttTopics::from_string(topic).filter(|p| p.kind == M::TOPIC) else {
+            warn!("Expected a message on {}, got one on {}", M::TOPIC, topic);
+            return Err(Error::Decode);
+        };
+        parsed
+            .content_type
+            .decode(payload)
+            .inspect_err(|_| warn!("Could not decode message on topic {}", topic))
+    }
+
+    /// Sends a PINGREQ and waits for the PINGRESP, used to detect a dead session.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.client.send_ping().await.map_err(log_error)
+    }
+
+    /// Waits for the next message. Use [`payload_text`] to read a text payload.
+    pub async fn receive_message(&mut self) -> Result<(&str, &[u8]), Error> {
+        self.client.receive_message().await.map_err(log_error)
+    }
+}
+
+impl<'a, T, R> HypedMqttClient<'a, T, R>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write + embedded_io_async::ReadReady,
+    R: rand_core::RngCore,
+{
+    /// Like [`Self::receive_message`], but returns `None` instead of waiting
+    /// when nothing has arrived yet.
+    pub async fn try_receive_message(&mut self) -> Result<Option<(&str, &[u8])>, Error> {
+        self.client
+            .receive_message_if_ready()
+            .await
+            .map_err(log_error)
+    }
+}
+
+fn log_error(mqtt_error: ReasonCode) -> Error {
+    match mqtt_error {
+        ReasonCode::NetworkError => {
+            info!("MQTT Network Error");
+        }
+        _ => {
+            warn!("Other MQTT Error: {:?}", mqtt_error);
         }
     }
+    Error::from(mqtt_error)
 }
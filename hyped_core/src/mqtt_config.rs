@@ -0,0 +1,378 @@
+use core::fmt::Write;
+
+use defmt::Format;
+use heapless::String;
+use rust_mqtt::client::client_config::{ClientConfig, MqttVersion};
+pub use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+
+use crate::mqtt::{Error, MAX_PACKET_SIZE};
+use crate::mqtt_topics::{TopicNamespace, MAX_BOARD_LENGTH};
+
+/// Connection settings shared by the boards and the base station tools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MqttConfig<'a> {
+    /// Host name or, for the boards which have no DNS, an IPv4 address.
+    pub broker_host: &'a str,
+    pub broker_port: u16,
+    /// Every client id is this prefix followed by the role of the connection.
+    pub client_id_prefix: &'a str,
+    pub keep_alive_secs: u16,
+    /// Largest packet the broker may send this client. Anything smaller than
+    /// [`MAX_PACKET_SIZE`] makes the broker drop messages with long payloads.
+    pub max_packet_size: u32,
+    /// Used for subscriptions. Publishing uses the QoS of each topic's
+    /// [`crate::mqtt_topics::TopicPolicy`].
+    pub qos: QualityOfService,
+    /// Prefix of every topic, `team/vehicle/board`.
+    pub namespace: TopicNamespace<'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum ConfigError {
+    UnknownKey,
+    InvalidValue,
+}
+
+impl<'a> MqttConfig<'a> {
+    /// Sets one option by name, as used in config files and build time
+    /// variables. Keys are `broker_host`, `broker_port`, `client_id_prefix`,
+    /// `keep_alive_secs`, `max_packet_size`, `qos`, `team`, `vehicle` and
+    /// `board`.
+    pub fn set(&mut self, key: &str, value: &'a str) -> Result<(), ConfigError> {
+        match key {
+            "broker_host" => self.broker_host = non_empty(value)?,
+            "broker_port" => self.broker_port = parse(value)?,
+            "client_id_prefix" => self.client_id_prefix = non_empty(value)?,
+            "keep_alive_secs" => self.keep_alive_secs = parse(value)?,
+            "max_packet_size" => self.max_packet_size = parse(value)?,
+            "qos" => {
+                self.qos = match value {
+                    "0" => QualityOfService::QoS0,
+                    "1" => QualityOfService::QoS1,
+                    "2" => QualityOfService::QoS2,
+                    _ => return Err(ConfigError::InvalidValue),
+                }
+            }
+            "team" => self.namespace.team = topic_level(value)?,
+            "vehicle" => self.namespace.vehicle = topic_level(value)?,
+            "board" => self.namespace.board = board_id(value)?,
+            _ => return Err(ConfigError::UnknownKey),
+        }
+        Ok(())
+    }
+
+    /// The broker host as an IPv4 address, if it is one. Usable in constants,
+    /// so that a board's broker address is checked when it is built.
+    pub const fn broker_ipv4(&self) -> Option<[u8; 4]> {
+        let bytes = self.broker_host.as_bytes();
+        let mut address = [0; 4];
+        let mut index = 0;
+        let mut octet = 0;
+        while octet < address.len() {
+            if octet > 0 {
+                if index == bytes.len() || bytes[index] != b'.' {
+                    return None;
+                }
+                index += 1;
+            }
+            let start = index;
+            let mut value: u16 = 0;
+            while index < bytes.len() && bytes[index].is_ascii_digit() {
+                value = value * 10 + (bytes[index] - b'0') as u16;
+                if value > u8::MAX as u16 {
+                    return None;
+                }
+                index += 1;
+            }
+            if index == start {
+                return None;
+            }
+            address[octet] = value as u8;
+            octet += 1;
+        }
+        if index == bytes.len() {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    /// Client id for one connection, e.g. `hyped-receiver` for role `receiver`.
+    pub fn client_id<const N: usize>(&self, role: &str) -> Result<String<N>, Error> {
+        let mut client_id = String::new();
+        write!(client_id, "{}-{}", self.client_id_prefix, role)
+            .map_err(|_| Error::BufferOverflow)?;
+        Ok(client_id)
+    }
+
+    /// A rust-mqtt client config connecting as `client_id`.
+    pub fn client_config<'c, R: rand_core::RngCore>(
+        &self,
+        client_id: &'c str,
+        rng: R,
+    ) -> ClientConfig<'c, 5, R> {
+        let mut config = ClientConfig::new(MqttVersion::MQTTv5, rng);
+        config.add_max_subscribe_qos(self.qos);
+        config.add_client_id(client_id);
+        config.keep_alive = self.keep_alive_secs;
+        config.max_packet_size = self.max_packet_size;
+        config
+    }
+}
+
+impl MqttConfig<'static> {
+    pub const DEFAULT: MqttConfig<'static> = MqttConfig {
+        broker_host: "localhost",
+        broker_port: 1883,
+        client_id_prefix: "hyped",
+        keep_alive_secs: 60,
+        max_packet_size: MAX_PACKET_SIZE,
+        qos: QualityOfService::QoS1,
+        namespace: TopicNamespace {
+            team: "hyped",
+            vehicle: "cart_2024",
+            board: "board",
+        },
+    };
+
+    /// Overrides options with the `HYPED_MQTT_*` environment variables that
+    /// were set when the crate was built, e.g. `HYPED_MQTT_BROKER_HOST`. This
+    /// runs at compile time, so an invalid value fails the build, and statics
+    /// such as a board's logger name the same board as its topics.
+    pub const fn with_build_env(mut self) -> Self {
+        if let Some(host) = option_env!("HYPED_MQTT_BROKER_HOST") {
+            self.broker_host = const_non_empty(host);
+        }
+        if let Some(port) = option_env!("HYPED_MQTT_BROKER_PORT") {
+            self.broker_port = const_parse(port, u16::MAX as u32) as u16;
+        }
+        if let Some(prefix) = option_env!("HYPED_MQTT_CLIENT_ID_PREFIX") {
+            self.client_id_prefix = const_non_empty(prefix);
+        }
+        if let Some(keep_alive) = option_env!("HYPED_MQTT_KEEP_ALIVE_SECS") {
+            self.keep_alive_secs = const_parse(keep_alive, u16::MAX as u32) as u16;
+        }
+        if let Some(size) = option_env!("HYPED_MQTT_MAX_PACKET_SIZE") {
+            self.max_packet_size = const_parse(size, u32::MAX);
+        }
+        if let Some(qos) = option_env!("HYPED_MQTT_QOS") {
+            self.qos = match const_parse(qos, 2) {
+                0 => QualityOfService::QoS0,
+                1 => QualityOfService::QoS1,
+                _ => QualityOfService::QoS2,
+            };
+        }
+        if let Some(team) = option_env!("HYPED_MQTT_TEAM") {
+            self.namespace.team = const_topic_level(team);
+        }
+        if let Some(vehicle) = option_env!("HYPED_MQTT_VEHICLE") {
+            self.namespace.vehicle = const_topic_level(vehicle);
+        }
+        if let Some(board) = option_env!("HYPED_MQTT_BOARD") {
+            self.namespace.board = const_board_id(board);
+        }
+        self
+    }
+}
+
+impl Default for MqttConfig<'static> {
+    fn default() -> Self {
+        MqttConfig::DEFAULT
+    }
+}
+
+fn non_empty(value: &str) -> Result<&str, ConfigError> {
+    match value {
+        "" => Err(ConfigError::InvalidValue),
+        value => Ok(value),
+    }
+}
+
+/// A single topic level, which may not contain wildcards or separators.
+fn topic_level(value: &str) -> Result<&str, ConfigError> {
+    match non_empty(value)? {
+        value if value.contains(['/', '+', '#']) => Err(ConfigError::InvalidValue),
+        value => Ok(value),
+    }
+}
+
+/// A topic level short enough to be sent as the board id of a message.
+fn board_id(value: &str) -> Result<&str, ConfigError> {
+    match topic_level(value)? {
+        value if value.len() > MAX_BOARD_LENGTH => Err(ConfigError::InvalidValue),
+        value => Ok(value),
+    }
+}
+
+/// [`non_empty`] for constants, panicking on invalid values.
+const fn const_non_empty(value: &'static str) -> &'static str {
+    if value.is_empty() {
+        panic!("option is empty");
+    }
+    value
+}
+
+/// [`topic_level`] for constants, panicking on invalid values.
+const fn const_topic_level(value: &'static str) -> &'static str {
+    let bytes = const_non_empty(value).as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        if matches!(bytes[index], b'/' | b'+' | b'#') {
+            panic!("topic level contains '/', '+' or '#'");
+        }
+        index += 1;
+    }
+    value
+}
+
+/// [`parse`] of a decimal number up to `max` for constants, panicking on
+/// invalid values.
+const fn const_parse(value: &str, max: u32) -> u32 {
+    let bytes = value.as_bytes();
+    if bytes.is_empty() {
+        panic!("number is empty");
+    }
+    let mut number: u32 = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        if !bytes[index].is_ascii_digit() {
+            panic!("number contains a non-digit");
+        }
+        number = match number.checked_mul(10) {
+            Some(number) => match number.checked_add((bytes[index] - b'0') as u32) {
+                Some(number) => number,
+                None => panic!("number is out of range"),
+            },
+            None => panic!("number is out of range"),
+        };
+        index += 1;
+    }
+    if number > max {
+        panic!("number is out of range");
+    }
+    number
+}
+
+/// [`board_id`] for constants, panicking on invalid values.
+const fn const_board_id(value: &'static str) -> &'static str {
+    if value.len() > MAX_BOARD_LENGTH {
+        panic!("board id is too long");
+    }
+    const_topic_level(value)
+}
+
+fn parse<T: core::str::FromStr>(value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::catch_unwind;
+
+    use super::{const_board_id, const_parse, const_topic_level, ConfigError, MqttConfig};
+    use crate::mqtt::MAX_PAYLOAD_SIZE;
+    use crate::mqtt_topics::MAX_TOPIC_LENGTH;
+
+    #[test]
+    fn default_packet_size_fits_the_largest_message() {
+        // Fixed header, topic length and packet id of a PUBLISH.
+        let header = 5 + 2 + 2;
+        let largest = header + MAX_TOPIC_LENGTH + MAX_PAYLOAD_SIZE;
+        assert!(MqttConfig::DEFAULT.max_packet_size as usize > largest);
+    }
+
+    #[test]
+    fn build_env_matches_runtime_overrides() {
+        const CONFIG: MqttConfig<'static> = MqttConfig::DEFAULT.with_build_env();
+        let mut runtime = MqttConfig::DEFAULT;
+        let overrides = [
+            ("broker_host", option_env!("HYPED_MQTT_BROKER_HOST")),
+            ("broker_port", option_env!("HYPED_MQTT_BROKER_PORT")),
+            (
+                "client_id_prefix",
+                option_env!("HYPED_MQTT_CLIENT_ID_PREFIX"),
+            ),
+            ("keep_alive_secs", option_env!("HYPED_MQTT_KEEP_ALIVE_SECS")),
+            ("max_packet_size", option_env!("HYPED_MQTT_MAX_PACKET_SIZE")),
+            ("qos", option_env!("HYPED_MQTT_QOS")),
+            ("team", option_env!("HYPED_MQTT_TEAM")),
+            ("vehicle", option_env!("HYPED_MQTT_VEHICLE")),
+            ("board", option_env!("HYPED_MQTT_BOARD")),
+        ];
+        for (key, value) in overrides {
+            if let Some(value) = value {
+                runtime.set(key, value).unwrap();
+            }
+        }
+        assert_eq!(CONFIG, runtime);
+    }
+
+    #[test]
+    fn const_helpers_work_in_constants() {
+        const PORT: u32 = const_parse("1883", u16::MAX as u32);
+        const LARGEST: u32 = const_parse("4294967295", u32::MAX);
+        const BOARD: &str = const_board_id("telemetry");
+        const TEAM: &str = const_topic_level("hyped");
+        assert_eq!((PORT, LARGEST), (1883, u32::MAX));
+        assert_eq!((BOARD, TEAM), ("telemetry", "hyped"));
+    }
+
+    /// The build time variables are checked as strictly as config files.
+    #[test]
+    fn const_helpers_reject_what_set_rejects() {
+        let numbers = [
+            "0",
+            "1883",
+            "65535",
+            "65536",
+            "",
+            "18 83",
+            "-1",
+            "0x10",
+            "4294967296",
+        ];
+        for value in numbers {
+            let mut config = MqttConfig::DEFAULT;
+            let parsed = catch_unwind(|| const_parse(value, u16::MAX as u32) as u16);
+            let set = config.set("broker_port", value).map(|_| config.broker_port);
+            assert_eq!(parsed.ok(), set.ok(), "{:?}", value);
+        }
+        let boards = [
+            "stm",
+            "a-board-id-16chr",
+            "a-board-id-too-long",
+            "",
+            "a/b",
+            "+",
+            "#",
+        ];
+        for value in boards {
+            let mut config = MqttConfig::DEFAULT;
+            let parsed = catch_unwind(|| const_board_id(value));
+            let set = config.set("board", value).map(|_| config.namespace.board);
+            assert_eq!(parsed.ok(), set.ok(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn broker_host_is_parsed_as_ipv4() {
+        let mut config = MqttConfig::DEFAULT;
+        config.broker_host = "169.254.195.141";
+        assert_eq!(config.broker_ipv4(), Some([169, 254, 195, 141]));
+        for host in ["localhost", "1.2.3", "1.2.3.4.", "1.2.3.256", "1..3.4", ""] {
+            config.broker_host = host;
+            assert_eq!(config.broker_ipv4(), None);
+        }
+    }
+
+    #[test]
+    fn namespace_levels_are_validated() {
+        let mut config = MqttConfig::DEFAULT;
+        config.set("board", "lim").unwrap();
+        assert_eq!(config.namespace.board, "lim");
+        for value in ["", "a/b", "+", "#", "a-board-id-too-long"] {
+            assert_eq!(config.set("board", value), Err(ConfigError::InvalidValue));
+        }
+        assert_eq!(config.namespace.board, "lim");
+    }
+}
@@ -0,0 +1,315 @@
+use core::cmp::min;
+use core::future::Future;
+
+use defmt::*;
+use heapless::Vec;
+use rust_mqtt::client::{client::MqttClient, client_config::ClientConfig};
+
+use crate::mqtt::{Error, HypedMqttClient};
+use crate::mqtt_topics::{TopicFilter, TopicNamespace};
+
+/// A transport that can (re-)open its underlying connection, e.g. a TCP socket
+/// that is aborted and connected to the broker again.
+pub trait Transport: embedded_io_async::Read + embedded_io_async::Write {
+    fn reconnect(&mut self) -> impl Future<Output = Result<(), Error>>;
+}
+
+/// Async sleep, provided by the runtime (embassy on the board, tokio on the host).
+pub trait Delay {
+    fn delay_ms(&mut self, millis: u64) -> impl Future<Output = ()>;
+}
+
+/// The work done while connected to the broker. `run` returns the reason the
+/// session ended, after which the supervisor reconnects.
+pub trait MqttSession<T, R>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    R: rand_core::RngCore,
+{
+    fn run(&mut self, client: &mut HypedMqttClient<'_, T, R>) -> impl Future<Output = Error>;
+}
+
+/// Exponential backoff with jitter. Each delay is drawn uniformly from the
+/// upper half of the current step, and the step doubles up to `max_ms`.
+pub struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+    current_ms: u64,
+}
+
+impl Backoff {
+    pub const fn new(initial_ms: u64, max_ms: u64) -> Self {
+        Backoff {
+            initial_ms,
+            max_ms,
+            current_ms: initial_ms,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current_ms = self.initial_ms;
+    }
+
+    pub fn next_delay_ms(&mut self, rng: &mut impl rand_core::RngCore) -> u64 {
+        let step = self.current_ms;
+        self.current_ms = min(step.saturating_mul(2), self.max_ms);
+        let half = step / 2;
+        half + rng.next_u64() % (step - half + 1)
+    }
+}
+
+/// Keeps an MQTT session alive: (re)opens the transport, sends CONNECT,
+/// replays all subscriptions and hands the client to an [`MqttSession`]. When
+/// the session ends it waits according to the [`Backoff`] and starts over.
+pub struct MqttSupervisor<'a, 'c, T, D, R, J, F, const SUBS: usize>
+where
+    T: Transport,
+    D: Delay,
+    R: rand_core::RngCore,
+    J: rand_core::RngCore,
+    F: FnMut() -> ClientConfig<'c, 5, R>,
+{
+    transport: T,
+    delay: D,
+    jitter: J,
+    config: F,
+    namespace: TopicNamespace<'a>,
+    backoff: Backoff,
+    write_buffer: &'a mut [u8],
+    recv_buffer: &'a mut [u8],
+    subscriptions: Vec<TopicFilter, SUBS>,
+}
+
+impl<'a, 'c, T, D, R, J, F, const SUBS: usize> MqttSupervisor<'a, 'c, T, D, R, J, F, SUBS>
+where
+    T: Transport,
+    D: Delay,
+    R: rand_core::RngCore,
+    J: rand_core::RngCore,
+    F: FnMut() -> ClientConfig<'c, 5, R>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        transport: T,
+        delay: D,
+        jitter: J,
+        config: F,
+        namespace: TopicNamespace<'a>,
+        backoff: Backoff,
+        write_buffer: &'a mut [u8],
+        recv_buffer: &'a mut [u8],
+    ) -> Self {
+        MqttSupervisor {
+            transport,
+            delay,
+            jitter,
+            config,
+            namespace,
+            backoff,
+            write_buffer,
+            recv_buffer,
+            subscriptions: Vec::new(),
+        }
+    }
+
+    /// Adds a filter that is subscribed to every time the session is established.
+    pub fn add_subscription(&mut self, filter: TopicFilter) -> Result<(), Error> {
+        self.subscriptions
+            .push(filter)
+            .map_err(|_| Error::BufferOverflow)
+    }
+
+    pub async fn run<S>(&mut self, session: &mut S) -> !
+    where
+        S: for<'b> MqttSession<&'b mut T, R>,
+    {
+        loop {
+            let error = self.run_once(session).await;
+            warn!("MQTT session ended: {:?}", error);
+            let delay = self.backoff.next_delay_ms(&mut self.jitter);
+            info!("Reconnecting to MQTT broker in {} ms", delay);
+            self.delay.delay_ms(delay).await;
+        }
+    }
+
+    async fn run_once<S>(&mut self, session: &mut S) -> Error
+    where
+        S: for<'b> MqttSession<&'b mut T, R>,
+    {
+        if let Err(error) = self.transport.reconnect().await {
+            return error;
+        }
+
+        let write_len = self.write_buffer.len();
+        let recv_len = self.recv_buffer.len();
+        let config = (self.config)();
+        let client = MqttClient::<_, 5, _>::new(
+            &mut self.transport,
+            &mut *self.write_buffer,
+            write_len,
+            &mut *self.recv_buffer,
+            recv_len,
+            config,
+        );
+        let mut mqtt_client = HypedMqttClient {
+            client,
+            namespace: self.namespace,
+        };
+
+        if let Err(error) = mqtt_client.connect_to_broker().await {
+            return error;
+        }
+        for filter in self.subscriptions.iter() {
+            if let Err(error) = mqtt_client.subscribe(filter.as_str()).await {
+                return error;
+            }
+        }
+        self.backoff.reset();
+
+        session.run(&mut mqtt_client).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use rust_mqtt::client::client_config::ClientConfig;
+    use rust_mqtt::utils::rng_generator::CountingRng;
+
+    use super::{Backoff, Delay, MqttSession, MqttSupervisor};
+    use crate::mqtt::{Error, HypedMqttClient};
+    use crate::mqtt_config::MqttConfig;
+    use crate::mqtt_loopback::{FakeBroker, Fault, LoopbackTransport};
+
+    struct NoDelay;
+
+    impl Delay for NoDelay {
+        async fn delay_ms(&mut self, _millis: u64) {}
+    }
+
+    /// Records what the broker has subscribed when the session starts, then
+    /// drops the connection and waits for the client to notice.
+    struct DroppingSession {
+        broker: FakeBroker,
+        subscriptions: Vec<Vec<String>>,
+    }
+
+    impl<T, R> MqttSession<T, R> for DroppingSession
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        R: rand_core::RngCore,
+    {
+        async fn run(&mut self, client: &mut HypedMqttClient<'_, T, R>) -> Error {
+            self.subscriptions.push(self.broker.subscriptions());
+            self.broker.drop_connection();
+            client.receive_message().await.unwrap_err()
+        }
+    }
+
+    type TestSupervisor<'a> = MqttSupervisor<
+        'a,
+        'static,
+        LoopbackTransport,
+        NoDelay,
+        CountingRng,
+        CountingRng,
+        fn() -> ClientConfig<'static, 5, CountingRng>,
+        2,
+    >;
+
+    /// Runs `test` with a supervisor of a client of `broker`, which has not
+    /// connected yet, and a session that drops the connection once it has.
+    fn with_supervisor(
+        test: impl FnOnce(&FakeBroker, &mut TestSupervisor<'_>, &mut DroppingSession),
+    ) {
+        let broker = FakeBroker::new();
+        let (mut write_buffer, mut recv_buffer) = ([0; 256], [0; 256]);
+        let mut supervisor = MqttSupervisor::new(
+            broker.transport(),
+            NoDelay,
+            CountingRng(0),
+            FakeBroker::client_config as fn() -> _,
+            MqttConfig::DEFAULT.namespace,
+            Backoff::new(100, 1000),
+            &mut write_buffer,
+            &mut recv_buffer,
+        );
+        let mut session = DroppingSession {
+            broker: broker.clone(),
+            subscriptions: Vec::new(),
+        };
+        test(&broker, &mut supervisor, &mut session);
+    }
+
+    #[test]
+    fn backoff_grows_and_resets() {
+        let mut backoff = Backoff::new(100, 1000);
+        let mut rng = CountingRng(0);
+        for step in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay_ms(&mut rng);
+            assert!((step / 2..=step).contains(&delay), "{delay} not in {step}");
+        }
+        backoff.reset();
+        assert!(backoff.next_delay_ms(&mut rng) <= 100);
+    }
+
+    #[test]
+    fn refused_connection() {
+        with_supervisor(|broker, supervisor, session| {
+            broker.push_fault(Fault::RefuseConnection);
+            assert_eq!(block_on(supervisor.run_once(session)), Error::Network);
+            assert_eq!(broker.connect_count(), 0);
+            assert!(session.subscriptions.is_empty());
+
+            broker.push_fault(Fault::RejectConnect(0x87));
+            assert!(matches!(
+                block_on(supervisor.run_once(session)),
+                Error::Rejected(_)
+            ));
+            assert!(session.subscriptions.is_empty());
+        });
+    }
+
+    #[test]
+    fn backoff_resets_once_connected() {
+        with_supervisor(|broker, supervisor, session| {
+            let mut rng = CountingRng(0);
+            for _ in 0..4 {
+                supervisor.backoff.next_delay_ms(&mut rng);
+            }
+            broker.push_fault(Fault::RefuseConnection);
+            block_on(supervisor.run_once(session));
+            assert!(supervisor.backoff.next_delay_ms(&mut rng) > 500);
+
+            block_on(supervisor.run_once(session));
+            assert!(supervisor.backoff.next_delay_ms(&mut rng) <= 100);
+        });
+    }
+
+    #[test]
+    fn subscriptions_replayed_after_drop() {
+        with_supervisor(|broker, supervisor, session| {
+            let namespace = MqttConfig::DEFAULT.namespace;
+            supervisor
+                .add_subscription(namespace.navigation().unwrap())
+                .unwrap();
+            supervisor
+                .add_subscription(namespace.measurements().unwrap())
+                .unwrap();
+            assert_eq!(
+                supervisor.add_subscription(namespace.vehicle().unwrap()),
+                Err(Error::BufferOverflow)
+            );
+
+            for _ in 0..2 {
+                assert!(block_on(supervisor.run_once(session)).is_connection_lost());
+            }
+            assert_eq!(broker.connect_count(), 2);
+            let expected = [
+                "hyped/cart_2024/+/navigation/#",
+                "hyped/cart_2024/+/measurement/#",
+            ];
+            assert_eq!(session.subscriptions, [expected, expected]);
+        });
+    }
+}
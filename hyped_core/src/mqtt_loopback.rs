@@ -0,0 +1,644 @@
+//! In-memory transport and scripted fake broker, so that the MQTT client can
+//! be exercised by `cargo test` on the host without a board or a real broker.
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Poll, Waker};
+
+use rust_mqtt::client::client::MqttClient;
+use rust_mqtt::client::client_config::ClientConfig;
+use rust_mqtt::utils::rng_generator::CountingRng;
+
+use crate::mqtt::{Error, HypedMqttClient};
+use crate::mqtt_config::MqttConfig;
+use crate::mqtt_connection::Transport;
+use crate::mqtt_topics::TopicFilter;
+
+const CONNECT: u8 = 1;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const UNSUBSCRIBE: u8 = 10;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+/// Something going wrong on the broker side. Faults are queued with
+/// [`FakeBroker::push_fault`] and each one is used up by the first event it
+/// applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The next `reconnect` fails, as if the broker was unreachable.
+    RefuseConnection,
+    /// The connection is dropped instead of answering the next packet.
+    DropConnection,
+    /// The next CONNECT is answered with this reason code.
+    RejectConnect(u8),
+    /// The next SUBSCRIBE is answered with this reason code.
+    RejectSubscribe(u8),
+    /// The next QoS 1 PUBLISH is answered with this reason code.
+    RejectPublish(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopbackError {
+    ConnectionReset,
+}
+
+impl embedded_io_async::Error for LoopbackError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            LoopbackError::ConnectionReset => embedded_io_async::ErrorKind::ConnectionReset,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    connected: bool,
+    to_client: VecDeque<u8>,
+    from_client: Vec<u8>,
+    reader: Option<Waker>,
+    faults: VecDeque<Fault>,
+    connects: usize,
+    subscriptions: Vec<String>,
+    published: Vec<(String, Vec<u8>)>,
+    pings: usize,
+}
+
+/// A broker that answers CONNECT, SUBSCRIBE, UNSUBSCRIBE, PUBLISH and PINGREQ
+/// from a single client. Cloning it gives another handle to the same broker.
+#[derive(Clone, Default)]
+pub struct FakeBroker {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl FakeBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A transport connected to this broker, to be handed to the client.
+    pub fn transport(&self) -> LoopbackTransport {
+        LoopbackTransport {
+            state: self.state.clone(),
+        }
+    }
+
+    /// The config of every test client, [`MqttConfig::DEFAULT`] with a fixed
+    /// client id and random numbers.
+    pub fn client_config() -> ClientConfig<'static, 5, CountingRng> {
+        MqttConfig::DEFAULT.client_config("hyped-test", CountingRng(0))
+    }
+
+    /// A client whose transport is connected to this broker. It has not sent
+    /// CONNECT yet, so that tests can still queue faults for it.
+    pub fn connect_client<'a>(
+        &self,
+        write_buffer: &'a mut [u8],
+        recv_buffer: &'a mut [u8],
+    ) -> HypedMqttClient<'a, LoopbackTransport, CountingRng> {
+        let mut transport = self.transport();
+        embassy_futures::block_on(transport.reconnect()).unwrap();
+        let (write_len, recv_len) = (write_buffer.len(), recv_buffer.len());
+        HypedMqttClient {
+            client: MqttClient::new(
+                transport,
+                write_buffer,
+                write_len,
+                recv_buffer,
+                recv_len,
+                Self::client_config(),
+            ),
+            namespace: MqttConfig::DEFAULT.namespace,
+        }
+    }
+
+    /// Runs `test` with a client that has connected to this broker, with
+    /// buffers large enough for any test message.
+    pub fn with_connected_client<T>(
+        &self,
+        test: impl FnOnce(&mut HypedMqttClient<'_, LoopbackTransport, CountingRng>) -> T,
+    ) -> T {
+        let (mut write_buffer, mut recv_buffer) = ([0; 512], [0; 512]);
+        let mut client = self.connect_client(&mut write_buffer, &mut recv_buffer);
+        embassy_futures::block_on(client.connect_to_broker()).unwrap();
+        test(&mut client)
+    }
+
+    pub fn push_fault(&self, fault: Fault) {
+        self.lock().faults.push_back(fault);
+    }
+
+    /// Drops the connection right away, failing any read the client is waiting on.
+    pub fn drop_connection(&self) {
+        self.lock().disconnect();
+    }
+
+    /// Sends a QoS 0 PUBLISH to the client if it is subscribed to `topic`.
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        let mut state = self.lock();
+        if state.connected
+            && state
+                .subscriptions
+                .iter()
+                .any(|filter| TopicFilter::new(filter).is_ok_and(|filter| filter.matches(topic)))
+        {
+            let mut body = Vec::new();
+            push_str(&mut body, topic);
+            body.push(0);
+            body.extend_from_slice(payload);
+            state.send(PUBLISH << 4, &body);
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.lock().connected
+    }
+
+    /// Number of CONNECT packets accepted so far.
+    pub fn connect_count(&self) -> usize {
+        self.lock().connects
+    }
+
+    /// Number of PINGREQ packets answered so far.
+    pub fn ping_count(&self) -> usize {
+        self.lock().pings
+    }
+
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.lock().subscriptions.clone()
+    }
+
+    /// Every message the client has published, in order.
+    pub fn published(&self) -> Vec<(String, Vec<u8>)> {
+        self.lock().published.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().unwrap()
+    }
+}
+
+pub struct LoopbackTransport {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl embedded_io_async::ErrorType for LoopbackTransport {
+    type Error = LoopbackError;
+}
+
+impl embedded_io_async::Read for LoopbackTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if !state.connected {
+                return Poll::Ready(Err(LoopbackError::ConnectionReset));
+            }
+            if state.to_client.is_empty() {
+                state.reader = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let count = buf.len().min(state.to_client.len());
+            for (byte, value) in buf.iter_mut().zip(state.to_client.drain(..count)) {
+                *byte = value;
+            }
+            Poll::Ready(Ok(count))
+        })
+        .await
+    }
+}
+
+impl embedded_io_async::ReadReady for LoopbackTransport {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        let state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(LoopbackError::ConnectionReset);
+        }
+        Ok(!state.to_client.is_empty())
+    }
+}
+
+impl embedded_io_async::Write for LoopbackTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(LoopbackError::ConnectionReset);
+        }
+        state.from_client.extend_from_slice(buf);
+        state.process();
+        Ok(buf.len())
+    }
+}
+
+impl Transport for LoopbackTransport {
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.disconnect();
+        if state.take_fault(|fault| fault == Fault::RefuseConnection) {
+            return Err(Error::Network);
+        }
+        state.connected = true;
+        Ok(())
+    }
+}
+
+impl BrokerState {
+    fn disconnect(&mut self) {
+        self.connected = false;
+        self.to_client.clear();
+        self.from_client.clear();
+        self.subscriptions.clear();
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn take_fault(&mut self, applies: impl Fn(Fault) -> bool) -> bool {
+        match self.faults.iter().position(|fault| applies(*fault)) {
+            Some(index) => {
+                self.faults.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_reject(&mut self, reject: fn(Fault) -> Option<u8>) -> Option<u8> {
+        let index = self
+            .faults
+            .iter()
+            .position(|fault| reject(*fault).is_some())?;
+        self.faults.remove(index).and_then(reject)
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) {
+        self.to_client.push_back(header);
+        let mut remaining = body.len();
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            self.to_client.push_back(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+        self.to_client.extend(body);
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    /// Handles every complete packet received from the client so far.
+    fn process(&mut self) {
+        while let Some((header, start, end)) = split_packet(&self.from_client) {
+            let packet: Vec<u8> = self.from_client.drain(..end).collect();
+            if self.take_fault(|fault| fault == Fault::DropConnection) {
+                self.disconnect();
+                return;
+            }
+            self.handle(header, &packet[start..]);
+            if !self.connected {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, header: u8, body: &[u8]) {
+        let mut reader = PacketReader { data: body };
+        match header >> 4 {
+            CONNECT => {
+                let reason = self
+                    .take_reject(|fault| match fault {
+                        Fault::RejectConnect(reason) => Some(reason),
+                        _ => None,
+                    })
+                    .unwrap_or(0);
+                if reason == 0 {
+                    self.connects += 1;
+                }
+                self.send(0x20, &[0, reason, 0]);
+            }
+            PUBLISH => {
+                let qos = (header >> 1) & 0x03;
+                let topic = reader.string();
+                let packet_id = if qos > 0 { reader.u16() } else { 0 };
+                reader.properties();
+                self.published.push((topic, reader.data.to_vec()));
+                if qos > 0 {
+                    let reason = self
+                        .take_reject(|fault| match fault {
+                            Fault::RejectPublish(reason) => Some(reason),
+                            _ => None,
+                        })
+                        .unwrap_or(0);
+                    let [high, low] = packet_id.to_be_bytes();
+                    self.send(0x40, &[high, low, reason, 0]);
+                }
+            }
+            SUBSCRIBE => {
+                let packet_id = reader.u16();
+                reader.properties();
+                let reject = self.take_reject(|fault| match fault {
+                    Fault::RejectSubscribe(reason) => Some(reason),
+                    _ => None,
+                });
+                let [high, low] = packet_id.to_be_bytes();
+                let mut ack = vec![high, low, 0];
+                while !reader.data.is_empty() {
+                    let filter = reader.string();
+                    let options = reader.u8();
+                    match reject {
+                        Some(reason) => ack.push(reason),
+                        None => {
+                            ack.push(options & 0x03);
+                            self.subscriptions.push(filter);
+                        }
+                    }
+                }
+                self.send(0x90, &ack);
+            }
+            UNSUBSCRIBE => {
+                let packet_id = reader.u16();
+                reader.properties();
+                let [high, low] = packet_id.to_be_bytes();
+                let mut ack = vec![high, low, 0];
+                while !reader.data.is_empty() {
+                    let filter = reader.string();
+                    self.subscriptions.retain(|existing| *existing != filter);
+                    ack.push(0);
+                }
+                self.send(0xB0, &ack);
+            }
+            PINGREQ => {
+                self.pings += 1;
+                self.send(0xD0, &[]);
+            }
+            DISCONNECT => self.disconnect(),
+            _ => {}
+        }
+    }
+}
+
+/// Returns the fixed header byte and the bounds of the variable header and
+/// payload of the first packet in `data`, if it has been received completely.
+fn split_packet(data: &[u8]) -> Option<(u8, usize, usize)> {
+    let header = *data.first()?;
+    let mut remaining = 0usize;
+    let mut multiplier = 1usize;
+    let mut index = 1;
+    loop {
+        let byte = *data.get(index)?;
+        remaining += (byte & 0x7F) as usize * multiplier;
+        multiplier *= 128;
+        index += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let end = index + remaining;
+    if data.len() < end {
+        return None;
+    }
+    Some((header, index, end))
+}
+
+struct PacketReader<'a> {
+    data: &'a [u8],
+}
+
+impl PacketReader<'_> {
+    fn u8(&mut self) -> u8 {
+        let (value, rest) = self.data.split_first().unwrap_or((&0, &[]));
+        self.data = rest;
+        *value
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes([self.u8(), self.u8()])
+    }
+
+    fn variable_int(&mut self) -> usize {
+        let mut value = 0usize;
+        let mut multiplier = 1usize;
+        loop {
+            let byte = self.u8();
+            value += (byte & 0x7F) as usize * multiplier;
+            multiplier *= 128;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> &[u8] {
+        let (value, rest) = self.data.split_at(count.min(self.data.len()));
+        self.data = rest;
+        value
+    }
+
+    fn string(&mut self) -> String {
+        let length = self.u16() as usize;
+        String::from_utf8_lossy(self.bytes(length)).into_owned()
+    }
+
+    fn properties(&mut self) {
+        let length = self.variable_int();
+        self.bytes(length);
+    }
+}
+
+fn push_str(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+    use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+
+    use super::*;
+    use crate::codec::{Codec, ContentType, Postcard};
+    use crate::mqtt::{ButtonMqttMessage, MqttMessage};
+    use crate::mqtt_config::MqttConfig;
+    use crate::mqtt_messages::StateMessage;
+    use crate::mqtt_topics::MqttTopics;
+    use crate::state_machine::PodState;
+
+    const NOT_AUTHORIZED: u8 = 0x87;
+
+    const IDLE: StateMessage = StateMessage {
+        previous: PodState::Calibrating,
+        current: PodState::Idle,
+        timestamp_ms: 1200,
+    };
+
+    /// The full topic `kind` is published on by the test client.
+    fn topic(kind: MqttTopics, content_type: ContentType) -> String {
+        let namespace = MqttConfig::DEFAULT.namespace;
+        namespace.topic(kind, content_type).unwrap().to_string()
+    }
+
+    #[test]
+    fn connect_rejected() {
+        let broker = FakeBroker::new();
+        broker.push_fault(Fault::RejectConnect(NOT_AUTHORIZED));
+        let (mut write, mut recv) = ([0; 256], [0; 256]);
+        let mut client = broker.connect_client(&mut write, &mut recv);
+
+        let error = block_on(client.connect_to_broker()).unwrap_err();
+        assert_eq!(error, Error::Rejected(ReasonCode::NotAuthorized));
+        assert!(!error.is_connection_lost());
+        assert_eq!(broker.connect_count(), 0);
+    }
+
+    #[test]
+    fn subscribe_rejected() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            broker.push_fault(Fault::RejectSubscribe(NOT_AUTHORIZED));
+            let error = block_on(client.subscribe("hyped/#")).unwrap_err();
+            assert!(matches!(error, Error::Rejected(_)));
+            assert!(broker.subscriptions().is_empty());
+
+            block_on(client.subscribe("hyped/#")).unwrap();
+            assert_eq!(broker.subscriptions(), ["hyped/#"]);
+        });
+    }
+
+    #[test]
+    fn publish_rejected() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            broker.push_fault(Fault::RejectPublish(NOT_AUTHORIZED));
+            let error = block_on(client.send_message(
+                "hyped/test",
+                b"rejected",
+                QualityOfService::QoS1,
+                false,
+            ))
+            .unwrap_err();
+            assert!(matches!(error, Error::Rejected(_)));
+            assert!(!error.is_connection_lost());
+
+            let message = MqttMessage::from_message(&IDLE).unwrap();
+            block_on(client.publish_message(&message)).unwrap();
+            let published = broker.published();
+            assert_eq!(published.len(), 2);
+            assert_eq!(published[1].0, "hyped/cart_2024/board/state/state");
+            assert_eq!(ContentType::Json.decode(&published[1].1), Ok(IDLE));
+        });
+    }
+
+    #[test]
+    fn publishes_typed_messages() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            block_on(client.publish(&IDLE)).unwrap();
+            block_on(client.publish_with::<Postcard, _>(&IDLE)).unwrap();
+            let published = broker.published();
+            assert_eq!(published.len(), 2);
+            for ((topic, payload), content_type) in published.iter().zip(ContentType::ALL) {
+                assert_eq!(*topic, self::topic(MqttTopics::State, content_type));
+                assert_eq!(content_type.decode(payload), Ok(IDLE));
+            }
+            assert!(published[1].0.ends_with("/postcard"));
+        });
+    }
+
+    #[test]
+    fn direct_publishes_are_not_rate_limited() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            // Button has a minimum interval, which only the session applies.
+            assert!(MqttTopics::Button.policy().min_interval_ms.is_some());
+            for task_id in 0..3 {
+                let button = ButtonMqttMessage {
+                    task_id,
+                    status: true,
+                };
+                block_on(client.publish(&button)).unwrap();
+            }
+            assert_eq!(broker.published().len(), 3);
+        });
+    }
+
+    #[test]
+    fn receives_typed_messages() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            block_on(client.subscribe("hyped/#")).unwrap();
+
+            // Decoded in the encoding named by the topic.
+            for content_type in ContentType::ALL {
+                let mut payload = [0; 64];
+                let length = content_type.encode(&IDLE, &mut payload).unwrap();
+                broker.publish(&topic(MqttTopics::State, content_type), &payload[..length]);
+                assert_eq!(block_on(client.receive::<StateMessage>()), Ok(IDLE));
+            }
+
+            // On the topic of another message type.
+            let button = ButtonMqttMessage {
+                task_id: 1,
+                status: true,
+            };
+            let mut payload = [0; 64];
+            let length = Postcard::encode(&button, &mut payload).unwrap();
+            broker.publish(
+                &topic(MqttTopics::Button, ContentType::Postcard),
+                &payload[..length],
+            );
+            assert_eq!(
+                block_on(client.receive::<StateMessage>()),
+                Err(Error::Decode)
+            );
+
+            // Not a StateMessage in the topic's encoding.
+            broker.publish(&topic(MqttTopics::State, ContentType::Json), b"idle");
+            assert_eq!(
+                block_on(client.receive::<StateMessage>()),
+                Err(Error::Decode)
+            );
+            broker.publish(&topic(MqttTopics::State, ContentType::Postcard), &[]);
+            assert_eq!(
+                block_on(client.receive::<StateMessage>()),
+                Err(Error::Decode)
+            );
+        });
+    }
+
+    #[test]
+    fn receives_subscribed_messages() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            block_on(client.subscribe("hyped/+/base/#")).unwrap();
+
+            broker.publish("hyped/cart_2024/other/state/state", b"ignored");
+            broker.publish("hyped/cart_2024/base/state/state", b"running");
+            let (topic, payload) = block_on(client.receive_message()).unwrap();
+            assert_eq!(topic, "hyped/cart_2024/base/state/state");
+            assert_eq!(payload, b"running");
+        });
+    }
+
+    #[test]
+    fn connection_dropped() {
+        let broker = FakeBroker::new();
+        broker.with_connected_client(|client| {
+            broker.push_fault(Fault::DropConnection);
+            let error =
+                block_on(client.send_message("hyped/test", b"lost", QualityOfService::QoS1, false))
+                    .unwrap_err();
+            assert!(error.is_connection_lost());
+            assert!(!broker.is_connected());
+            assert!(broker.published().is_empty());
+
+            let error = block_on(client.receive_message()).unwrap_err();
+            assert!(error.is_connection_lost());
+        });
+    }
+}
@@ -0,0 +1,244 @@
+use heapless::String;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    logger::{LogLevel, LogTarget},
+    mqtt_topics::{MqttTopics, MAX_BOARD_LENGTH},
+    state_machine::PodState,
+};
+
+/// Binds a payload type to the one topic it is published on, so that the
+/// typed client methods pick the topic from the message type. Implemented by
+/// the topic list in [`crate::mqtt_topics`].
+pub trait TopicMessage: Serialize + DeserializeOwned {
+    const TOPIC: MqttTopics;
+}
+
+// Payloads for each of the MqttTopics. Timestamps are milliseconds since the
+// sending board booted, distances are in metres and times in seconds.
+
+/// One sample from a 3-axis accelerometer, in m/s^2.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AccelerometerMessage {
+    pub sensor_id: u8,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub timestamp_ms: u64,
+}
+
+/// Movement seen by an optical flow sensor since its previous sample, in metres.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OpticalFlowMessage {
+    pub sensor_id: u8,
+    pub delta_x: f32,
+    pub delta_y: f32,
+    pub timestamp_ms: u64,
+}
+
+/// Number of track stripes a keyence sensor has counted since boot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeyenceMessage {
+    pub sensor_id: u8,
+    pub stripe_count: u32,
+    pub timestamp_ms: u64,
+}
+
+/// Navigation estimate of the distance travelled along the track.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DisplacementMessage {
+    pub displacement: f32,
+    pub timestamp_ms: u64,
+}
+
+/// Navigation estimate of the velocity along the track, in m/s.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct VelocityMessage {
+    pub velocity: f32,
+    pub timestamp_ms: u64,
+}
+
+/// Navigation estimate of the acceleration along the track, in m/s^2.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AccelerationMessage {
+    pub acceleration: f32,
+    pub timestamp_ms: u64,
+}
+
+/// A state transition that has taken place.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct StateMessage {
+    pub previous: PodState,
+    pub current: PodState,
+    pub timestamp_ms: u64,
+}
+
+/// A request, e.g. from the base station, to move the pod into another state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct StateRequestMessage {
+    pub requested: PodState,
+}
+
+/// Sent by every board at a fixed interval, see [`crate::heartbeat`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatMessage {
+    pub board: String<MAX_BOARD_LENGTH>,
+    pub uptime_ms: u64,
+    /// Counts up by one per beat and starts from 0 when the board restarts.
+    pub sequence: u32,
+    /// One bit per task the board runs.
+    pub tasks: u32,
+    /// The bits of the tasks that checked in since the previous beat.
+    pub healthy: u32,
+}
+
+/// Changes the minimum level of one log target, switching it off if `level`
+/// is `None`. Applies to every board unless `board` is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogLevelMessage {
+    pub board: Option<String<MAX_BOARD_LENGTH>>,
+    pub target: LogTarget,
+    pub level: Option<LogLevel>,
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::Debug;
+
+    use super::*;
+    use crate::codec::{Codec, ContentType, Json, Postcard};
+    use crate::logger::LogRecord;
+    use crate::mqtt::{Error, MqttMessage, MAX_PAYLOAD_SIZE};
+
+    /// Encodes `message` in every content type and checks it decodes to the
+    /// same value, also when built into an [`MqttMessage`].
+    fn round_trip<M: TopicMessage + PartialEq + Debug>(message: M) {
+        for content_type in ContentType::ALL {
+            let mut buffer = [0; MAX_PAYLOAD_SIZE];
+            let length = content_type.encode(&message, &mut buffer).unwrap();
+            let decoded: M = content_type.decode(&buffer[..length]).unwrap();
+            assert_eq!(decoded, message, "{:?}", content_type);
+
+            let queued = match content_type {
+                ContentType::Json => MqttMessage::from_message(&message),
+                ContentType::Postcard => MqttMessage::from_message_with::<Postcard, M>(&message),
+            }
+            .unwrap();
+            assert_eq!(queued.kind, M::TOPIC);
+            assert_eq!(queued.content_type, content_type);
+            assert_eq!(&queued.payload[..], &buffer[..length]);
+        }
+    }
+
+    #[test]
+    fn measurements_round_trip() {
+        round_trip(AccelerometerMessage {
+            sensor_id: 2,
+            x: 0.5,
+            y: -9.81,
+            z: 1e-3,
+            timestamp_ms: u64::MAX,
+        });
+        round_trip(OpticalFlowMessage {
+            sensor_id: 1,
+            delta_x: 0.002,
+            delta_y: -0.25,
+            timestamp_ms: 12,
+        });
+        round_trip(KeyenceMessage {
+            sensor_id: 0,
+            stripe_count: 4096,
+            timestamp_ms: 0,
+        });
+    }
+
+    #[test]
+    fn navigation_round_trips() {
+        round_trip(DisplacementMessage {
+            displacement: 101.25,
+            timestamp_ms: 5000,
+        });
+        round_trip(VelocityMessage {
+            velocity: -0.5,
+            timestamp_ms: 5010,
+        });
+        round_trip(AccelerationMessage {
+            acceleration: 3.75,
+            timestamp_ms: 5020,
+        });
+    }
+
+    #[test]
+    fn state_round_trips() {
+        round_trip(StateMessage {
+            previous: PodState::Ready,
+            current: PodState::Emergency,
+            timestamp_ms: 42,
+        });
+        round_trip(StateRequestMessage {
+            requested: PodState::Calibrating,
+        });
+    }
+
+    #[test]
+    fn health_and_settings_round_trip() {
+        round_trip(HeartbeatMessage {
+            board: String::try_from("stm").unwrap(),
+            uptime_ms: 3_600_000,
+            sequence: u32::MAX,
+            tasks: 0b111,
+            healthy: 0b101,
+        });
+        round_trip(LogLevelMessage {
+            board: Some(String::try_from("base-station").unwrap()),
+            target: LogTarget::Mqtt,
+            level: Some(LogLevel::Warn),
+        });
+        round_trip(LogLevelMessage {
+            board: None,
+            target: LogTarget::Console,
+            level: None,
+        });
+    }
+
+    #[test]
+    fn escaped_strings_round_trip() {
+        let message = "say \"hi\"\nok\t\\ \u{1b}[0m caf\u{e9}";
+        let record = LogRecord {
+            level: LogLevel::Info,
+            board: String::try_from("stm").unwrap(),
+            source: String::try_from("logger \"test\"").unwrap(),
+            timestamp_ms: 1,
+            sequence: 2,
+            message: String::try_from(message).unwrap(),
+        };
+        let mut buffer = [0; MAX_PAYLOAD_SIZE];
+        let length = Json::encode(&record, &mut buffer).unwrap();
+        let json = core::str::from_utf8(&buffer[..length]).unwrap();
+        assert!(
+            json.contains(r#""say \"hi\"\nok\t\\ \u001B[0m café""#),
+            "{}",
+            json
+        );
+        round_trip(record);
+    }
+
+    #[test]
+    fn json_is_readable() {
+        let message = StateRequestMessage {
+            requested: PodState::Braking,
+        };
+        let mut buffer = [0; MAX_PAYLOAD_SIZE];
+        let length = Json::encode(&message, &mut buffer).unwrap();
+        assert_eq!(&buffer[..length], br#"{"requested":"Braking"}"#);
+    }
+
+    #[test]
+    fn wrong_encoding_is_a_decode_error() {
+        let payload = br#"{"requested":"Braking"}"#;
+        let decoded: Result<StateRequestMessage, _> = ContentType::Postcard.decode(payload);
+        assert_eq!(decoded, Err(Error::Decode));
+        let decoded: Result<StateRequestMessage, _> = ContentType::Json.decode(&[6]);
+        assert_eq!(decoded, Err(Error::Decode));
+    }
+}
@@ -0,0 +1,447 @@
+use core::future::Future;
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use crate::{
+    mqtt::{Error, HypedMqttClient, MqttMessage},
+    mqtt_connection::{Delay, MqttSession},
+    mqtt_topics::MqttTopics,
+    priority_channel::PriorityChannel,
+};
+
+/// How long the session waits for an outgoing message before it checks the
+/// connection for incoming messages again.
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Handles the messages arriving on the subscribed topics.
+pub trait MessageHandler {
+    /// Handles one message and returns the reply to publish, if any, e.g. the
+    /// state change a request caused. The handler runs in the session's task,
+    /// so it must not wait for room in the session's queue.
+    fn handle(&mut self, topic: &str, payload: &[u8]) -> impl Future<Output = Option<MqttMessage>>;
+
+    /// Called when a session starts, after the subscriptions are in place.
+    fn connected(&mut self) {}
+
+    /// Called when a session ends, before the supervisor reconnects.
+    fn disconnected(&mut self) {}
+}
+
+/// A session that both publishes everything sent to `queue` and passes
+/// incoming messages to `handler`, so that a board only needs one connection.
+///
+/// Messages are published according to their topic's policy, and dropped if
+/// they were produced faster than its rate limit. `clock` returns
+/// milliseconds and stamps the messages that were not stamped by their
+/// producer with [`MqttMessage::created_at`].
+///
+/// When nothing has been sent for half of `keep_alive_secs`, the broker is
+/// pinged, and a failed ping ends the session like any other lost connection.
+///
+/// rust-mqtt drops a message that arrives while it waits for the PUBACK of a
+/// QoS 1 publish or a PINGRESP, so incoming messages are read before every
+/// publish to keep that window small.
+pub struct MultiplexedSession<'q, M, D, H, const HIGH: usize, const NORMAL: usize>
+where
+    M: RawMutex,
+    D: Delay,
+    H: MessageHandler,
+{
+    queue: &'q PriorityChannel<M, MqttMessage, HIGH, NORMAL>,
+    delay: D,
+    clock: fn() -> u64,
+    /// Idle time after which the broker is pinged, `None` if keep alive is off.
+    ping_interval_ms: Option<u64>,
+    /// When something was last sent to the broker.
+    last_sent_ms: u64,
+    handler: H,
+    /// When the last message published on each topic was produced, indexed
+    /// like [`MqttTopics::ALL`].
+    last_published_ms: [Option<u64>; MqttTopics::ALL.len()],
+}
+
+impl<'q, M, D, H, const HIGH: usize, const NORMAL: usize>
+    MultiplexedSession<'q, M, D, H, HIGH, NORMAL>
+where
+    M: RawMutex,
+    D: Delay,
+    H: MessageHandler,
+{
+    pub fn new(
+        queue: &'q PriorityChannel<M, MqttMessage, HIGH, NORMAL>,
+        delay: D,
+        clock: fn() -> u64,
+        keep_alive_secs: u16,
+        handler: H,
+    ) -> Self {
+        MultiplexedSession {
+            queue,
+            delay,
+            clock,
+            ping_interval_ms: match keep_alive_secs {
+                0 => None,
+                secs => Some(u64::from(secs) * 500),
+            },
+            last_sent_ms: 0,
+            handler,
+            last_published_ms: [None; MqttTopics::ALL.len()],
+        }
+    }
+
+    async fn publish_and_dispatch<T, R>(&mut self, client: &mut HypedMqttClient<'_, T, R>) -> Error
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write + embedded_io_async::ReadReady,
+        R: rand_core::RngCore,
+    {
+        self.last_sent_ms = (self.clock)();
+        loop {
+            let next = select(self.queue.receive(), self.delay.delay_ms(POLL_INTERVAL_MS)).await;
+            if let Err(err) = self.dispatch_incoming(client).await {
+                return err;
+            }
+            let sent = match next {
+                Either::First(message) => self.publish(client, &message).await,
+                Either::Second(()) => self.ping_if_idle(client).await,
+            };
+            if let Err(err) = sent {
+                return err;
+            }
+        }
+    }
+
+    /// Pings the broker if nothing was sent for the ping interval, so that a
+    /// dead connection is noticed even when there is nothing to publish.
+    async fn ping_if_idle<T, R>(
+        &mut self,
+        client: &mut HypedMqttClient<'_, T, R>,
+    ) -> Result<(), Error>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        R: rand_core::RngCore,
+    {
+        let now_ms = (self.clock)();
+        match self.ping_interval_ms {
+            Some(interval_ms) if now_ms.saturating_sub(self.last_sent_ms) >= interval_ms => {}
+            _ => return Ok(()),
+        }
+        self.last_sent_ms = now_ms;
+        match client.ping().await {
+            Ok(()) => Ok(()),
+            Err(err) if err.is_connection_lost() => Err(err),
+            Err(err) => {
+                warn!("Ping failed, reconnecting: {:?}", err);
+                Err(Error::Network)
+            }
+        }
+    }
+
+    /// Publishes `message` unless it is above its topic's rate limit. Only a
+    /// lost connection is returned, on other errors the message is dropped.
+    async fn publish<T, R>(
+        &mut self,
+        client: &mut HypedMqttClient<'_, T, R>,
+        message: &MqttMessage,
+    ) -> Result<(), Error>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        R: rand_core::RngCore,
+    {
+        let created_ms = message.created_ms.unwrap_or_else(self.clock);
+        if self.is_rate_limited(message.kind, created_ms) {
+            debug!("Dropping message on {} above its rate limit", message.kind);
+            return Ok(());
+        }
+        self.last_sent_ms = (self.clock)();
+        match client.publish_message(message).await {
+            Ok(()) => {}
+            Err(err) if err.is_connection_lost() => return Err(err),
+            Err(Error::Rejected(reason)) => {
+                debug!("Broker rejected message on {}: {:?}", message.kind, reason)
+            }
+            Err(err) => warn!("Dropping message on {}: {:?}", message.kind, err),
+        }
+        Ok(())
+    }
+
+    /// Applies the `min_interval_ms` of the topic's policy to a message
+    /// produced at `created_ms`, recording the time if it may be published.
+    fn is_rate_limited(&mut self, kind: MqttTopics, created_ms: u64) -> bool {
+        let Some(interval_ms) = kind.policy().min_interval_ms else {
+            return false;
+        };
+        let last_ms = &mut self.last_published_ms[kind as usize];
+        if matches!(*last_ms, Some(last) if created_ms.saturating_sub(last) < u64::from(interval_ms))
+        {
+            return true;
+        }
+        *last_ms = Some(created_ms);
+        false
+    }
+
+    async fn dispatch_incoming<T, R>(
+        &mut self,
+        client: &mut HypedMqttClient<'_, T, R>,
+    ) -> Result<(), Error>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write + embedded_io_async::ReadReady,
+        R: rand_core::RngCore,
+    {
+        loop {
+            match client.try_receive_message().await {
+                Ok(Some((topic, payload))) => {
+                    if let Some(reply) = self.handler.handle(topic, payload).await {
+                        self.publish(client, &reply).await?;
+                    }
+                }
+                Ok(None) => return Ok(()),
+                Err(err) if err.is_connection_lost() => return Err(err),
+                Err(err) => warn!("Dropping incoming message: {:?}", err),
+            }
+        }
+    }
+}
+
+impl<'q, T, R, M, D, H, const HIGH: usize, const NORMAL: usize> MqttSession<T, R>
+    for MultiplexedSession<'q, M, D, H, HIGH, NORMAL>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write + embedded_io_async::ReadReady,
+    R: rand_core::RngCore,
+    M: RawMutex,
+    D: Delay,
+    H: MessageHandler,
+{
+    async fn run(&mut self, client: &mut HypedMqttClient<'_, T, R>) -> Error {
+        self.handler.connected();
+        let reason = self.publish_and_dispatch(client).await;
+        self.handler.disconnected();
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::{MessageHandler, MultiplexedSession};
+    use crate::codec::ContentType;
+    use crate::mqtt::{ButtonMqttMessage, Error, MqttMessage};
+    use crate::mqtt_config::MqttConfig;
+    use crate::mqtt_connection::{Delay, MqttSession};
+    use crate::mqtt_loopback::{FakeBroker, Fault};
+    use crate::mqtt_messages::{StateMessage, StateRequestMessage};
+    use crate::mqtt_topics::MqttTopics;
+    use crate::priority_channel::{Priority, PriorityChannel};
+    use crate::state_machine::{PodState, StateMachine};
+
+    type Queue = PriorityChannel<NoopRawMutex, MqttMessage, 2, 8>;
+
+    /// Sends the next scripted message each time the session is idle, one at
+    /// a time as rust-mqtt drops messages arriving while it waits for a
+    /// PUBACK, and drops the connection once the script is done.
+    struct ScriptedDelay {
+        broker: FakeBroker,
+        script: VecDeque<(String, Vec<u8>)>,
+    }
+
+    impl Delay for ScriptedDelay {
+        async fn delay_ms(&mut self, _millis: u64) {
+            match self.script.pop_front() {
+                Some((topic, payload)) => self.broker.publish(&topic, &payload),
+                None => self.broker.drop_connection(),
+            }
+        }
+    }
+
+    /// Answers every accepted state request with the resulting transition.
+    struct StateHandler {
+        machine: StateMachine,
+    }
+
+    impl MessageHandler for StateHandler {
+        async fn handle(&mut self, topic: &str, payload: &[u8]) -> Option<MqttMessage> {
+            let parsed = MqttTopics::from_string(topic)?;
+            let request: StateRequestMessage = parsed.content_type.decode(payload).ok()?;
+            let transition = self.machine.handle_request(&request, 0).ok()?;
+            Some(MqttMessage::from_message(&transition).unwrap())
+        }
+    }
+
+    thread_local! {
+        static NOW_MS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    /// A clock that advances by a second each time it is read.
+    fn ticking_clock() -> u64 {
+        NOW_MS.with(|now| {
+            now.set(now.get() + 1000);
+            now.get()
+        })
+    }
+
+    /// A script of `polls` idle polls, on a topic nobody subscribed to.
+    fn idle(polls: usize) -> VecDeque<(String, Vec<u8>)> {
+        (0..polls)
+            .map(|_| ("hyped/idle".to_string(), Vec::new()))
+            .collect()
+    }
+
+    fn button(task_id: u8) -> ButtonMqttMessage {
+        ButtonMqttMessage {
+            task_id,
+            status: true,
+        }
+    }
+
+    /// Runs a session on a client subscribed to every state topic until the
+    /// broker runs out of `script`, returning why it ended.
+    fn run_session(
+        broker: &FakeBroker,
+        queue: &Queue,
+        script: VecDeque<(String, Vec<u8>)>,
+        clock: fn() -> u64,
+    ) -> Error {
+        broker.with_connected_client(|client| {
+            block_on(client.subscribe("hyped/+/+/state/#")).unwrap();
+            let delay = ScriptedDelay {
+                broker: broker.clone(),
+                script,
+            };
+            let handler = StateHandler {
+                machine: StateMachine::new(),
+            };
+            let mut session = MultiplexedSession::new(queue, delay, clock, 60, handler);
+            block_on(session.run(client))
+        })
+    }
+
+    fn state_request(requested: PodState) -> (String, Vec<u8>) {
+        let topic = MqttConfig::DEFAULT
+            .namespace
+            .topic(MqttTopics::StateRequest, ContentType::Json)
+            .unwrap();
+        let mut buffer = [0; 64];
+        let length = ContentType::Json
+            .encode(&StateRequestMessage { requested }, &mut buffer)
+            .unwrap();
+        (topic.to_string(), buffer[..length].to_vec())
+    }
+
+    #[test]
+    fn replies_do_not_wait_for_the_queue() {
+        // More accepted requests than the high lane holds, alternating
+        // between two states so that every one of them is a transition.
+        let requests = 12;
+        let script = (0..requests)
+            .map(|index| match index % 2 {
+                0 => state_request(PodState::Calibrating),
+                _ => state_request(PodState::Idle),
+            })
+            .collect();
+        let broker = FakeBroker::new();
+        assert!(run_session(&broker, &Queue::new(), script, || 0).is_connection_lost());
+
+        let published = broker.published();
+        assert_eq!(published.len(), requests);
+        for (topic, payload) in &published {
+            assert_eq!(topic, "hyped/cart_2024/board/state/state");
+            let _: StateMessage = ContentType::Json.decode(payload).unwrap();
+        }
+    }
+
+    #[test]
+    fn publishes_queued_messages_by_priority() {
+        let queue = Queue::new();
+        let button = MqttMessage::from_message(&button(1)).unwrap();
+        queue.try_send(button, Priority::Normal).ok().unwrap();
+        let state = MqttMessage::from_message(&StateMessage {
+            previous: PodState::Accelerating,
+            current: PodState::Emergency,
+            timestamp_ms: 5000,
+        })
+        .unwrap();
+        queue.try_send(state, Priority::High).ok().unwrap();
+
+        let broker = FakeBroker::new();
+        assert!(run_session(&broker, &queue, VecDeque::new(), || 0).is_connection_lost());
+
+        let topics: Vec<_> = broker
+            .published()
+            .into_iter()
+            .map(|(topic, _)| topic)
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "hyped/cart_2024/board/state/state",
+                "hyped/cart_2024/board/debug/button"
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limits_apply_to_when_messages_were_produced() {
+        // Produced every 100 ms but all published at once, apart from one
+        // that came too soon and one without a stamp, published at 1000 ms.
+        let queue = Queue::new();
+        for (created_ms, task_id) in [(0, 0), (100, 1), (150, 2), (195, 3)] {
+            let button = MqttMessage::from_message(&button(task_id)).unwrap();
+            queue
+                .try_send(button.created_at(created_ms), Priority::Normal)
+                .ok()
+                .unwrap();
+        }
+        let unstamped = MqttMessage::from_message(&button(4)).unwrap();
+        queue.try_send(unstamped, Priority::Normal).ok().unwrap();
+
+        let broker = FakeBroker::new();
+        assert!(run_session(&broker, &queue, VecDeque::new(), || 1000).is_connection_lost());
+
+        let task_ids: Vec<_> = broker
+            .published()
+            .into_iter()
+            .map(|(_, payload)| {
+                let button: ButtonMqttMessage = ContentType::Json.decode(&payload).unwrap();
+                button.task_id
+            })
+            .collect();
+        assert_eq!(task_ids, [0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn pings_when_idle() {
+        // 100 idle seconds with a 60 s keep alive, pinging every 30 s.
+        let broker = FakeBroker::new();
+        let queue = Queue::new();
+        assert!(run_session(&broker, &queue, idle(100), ticking_clock).is_connection_lost());
+        assert_eq!(broker.ping_count(), 3);
+        assert!(broker.published().is_empty());
+    }
+
+    #[test]
+    fn failed_ping_ends_the_session() {
+        let broker = FakeBroker::new();
+        let queue = Queue::new();
+        let delay = ScriptedDelay {
+            broker: broker.clone(),
+            script: idle(100),
+        };
+        let handler = StateHandler {
+            machine: StateMachine::new(),
+        };
+        let mut session = MultiplexedSession::new(&queue, delay, ticking_clock, 60, handler);
+        let error = broker.with_connected_client(|client| {
+            broker.push_fault(Fault::DropConnection);
+            block_on(session.run(client))
+        });
+        assert!(error.is_connection_lost());
+        // Ended by the first ping rather than by the end of the script.
+        assert_eq!(broker.ping_count(), 0);
+        assert!(NOW_MS.with(Cell::get) < 40_000);
+    }
+}
@@ -0,0 +1,75 @@
+//! Blocking TCP transport, so that the MQTT client can talk to a real broker
+//! on the host. Reads and writes block the thread, so the client has to be
+//! driven by a blocking executor such as `embassy_futures::block_on`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use crate::mqtt::Error;
+use crate::mqtt_connection::Transport;
+
+pub struct TcpTransport {
+    address: SocketAddr,
+    stream: Option<TcpStream>,
+}
+
+impl TcpTransport {
+    /// A transport for the broker at `address`. It connects on the first
+    /// [`Transport::reconnect`].
+    pub fn new(address: SocketAddr) -> Self {
+        TcpTransport {
+            address,
+            stream: None,
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut TcpStream, std::io::Error> {
+        self.stream
+            .as_mut()
+            .ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl embedded_io_async::ErrorType for TcpTransport {
+    type Error = std::io::Error;
+}
+
+impl embedded_io_async::Read for TcpTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.stream()?.read(buf)
+    }
+}
+
+impl embedded_io_async::ReadReady for TcpTransport {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        let stream = self.stream()?;
+        stream.set_nonblocking(true)?;
+        let ready = match stream.peek(&mut [0]) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        };
+        stream.set_nonblocking(false)?;
+        ready
+    }
+}
+
+impl embedded_io_async::Write for TcpTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.stream()?.write(buf)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.stream()?.flush()
+    }
+}
+
+impl Transport for TcpTransport {
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        self.stream = None;
+        let stream = TcpStream::connect(self.address).map_err(|_| Error::Network)?;
+        let _ = stream.set_nodelay(true);
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
@@ -1,78 +1,423 @@
-use core::str::FromStr;
+use core::fmt::{self, Write};
 
-#[cfg(not(feature = "std"))]
 use heapless::String;
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
 
-pub enum MqttTopics {
-    State,
-    StateRequest,
-    Accelerometer,
-    OpticalFlow,
-    Keyence,
-    Displacement,
-    Velocity,
-    Acceleration,
-    Logs,
-}
+use crate::codec::ContentType;
+use crate::logger::LogRecord;
+use crate::mqtt::{ButtonMqttMessage, Error};
+use crate::mqtt_messages::{
+    AccelerationMessage, AccelerometerMessage, DisplacementMessage, HeartbeatMessage,
+    KeyenceMessage, LogLevelMessage, OpticalFlowMessage, StateMessage, StateRequestMessage,
+    TopicMessage, VelocityMessage,
+};
 
-// Write functions that will convert to and from the MqttTopics enum
-impl MqttTopics {
-    #[cfg(not(feature = "std"))]
-    pub fn to_string(&self) -> String<48> {
-        match self {
-            MqttTopics::State => String::<48>::from_str("hyped/cart_2024/state/state").unwrap(),
-            MqttTopics::StateRequest => {
-                String::<48>::from_str("hyped/cart_2024/state/state_request").unwrap()
-            }
-            MqttTopics::Accelerometer => {
-                String::<48>::from_str("hyped/cart_2024/measurement/accelerometer").unwrap()
+/// Longest topic, including the namespace and a content type suffix.
+pub const MAX_TOPIC_LENGTH: usize = 64;
+
+/// Longest board id, which is sent in heartbeats and log records.
+pub const MAX_BOARD_LENGTH: usize = 16;
+
+/// Generates [`MqttTopics`] with its conversions, [`MqttTopics::ALL`], the
+/// policies and the [`TopicMessage`] impl of each payload type from one list,
+/// so that adding a topic is a single line below.
+macro_rules! topics {
+    ($($name:ident = $path:literal, $payload:ty, $policy:expr;)+) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+        pub enum MqttTopics {
+            $($name,)+
+        }
+
+        impl MqttTopics {
+            pub const ALL: [MqttTopics; [$(MqttTopics::$name),+].len()] =
+                [$(MqttTopics::$name),+];
+
+            /// The topic below the [`TopicNamespace`], e.g. `state/state`.
+            pub const fn path(&self) -> &'static str {
+                match self {
+                    $(MqttTopics::$name => $path,)+
+                }
             }
-            MqttTopics::OpticalFlow => {
-                String::<48>::from_str("hyped/cart_2024/measurement/optical_flow").unwrap()
+
+            pub fn from_path(path: &str) -> Option<MqttTopics> {
+                match path {
+                    $($path => Some(MqttTopics::$name),)+
+                    _ => None,
+                }
             }
-            MqttTopics::Keyence => {
-                String::<48>::from_str("hyped/cart_2024/measurement/keyence").unwrap()
+
+            /// How messages on this topic are published, looked up by the
+            /// clients when publishing.
+            pub const fn policy(&self) -> TopicPolicy {
+                match self {
+                    $(MqttTopics::$name => $policy,)+
+                }
             }
-            MqttTopics::Displacement => {
-                String::<48>::from_str("hyped/cart_2024/navigation/displacement").unwrap()
+        }
+
+        $(
+            impl TopicMessage for $payload {
+                const TOPIC: MqttTopics = MqttTopics::$name;
             }
-            MqttTopics::Velocity => {
-                String::<48>::from_str("hyped/cart_2024/navigation/velocity").unwrap()
+        )+
+    };
+}
+
+topics! {
+    State = "state/state", StateMessage, STATE;
+    StateRequest = "state/state_request", StateRequestMessage, COMMAND;
+    Accelerometer = "measurement/accelerometer", AccelerometerMessage, MEASUREMENT;
+    OpticalFlow = "measurement/optical_flow", OpticalFlowMessage, MEASUREMENT;
+    Keyence = "measurement/keyence", KeyenceMessage, MEASUREMENT;
+    Displacement = "navigation/displacement", DisplacementMessage, NAVIGATION;
+    Velocity = "navigation/velocity", VelocityMessage, NAVIGATION;
+    Acceleration = "navigation/acceleration", AccelerationMessage, NAVIGATION;
+    Logs = "logs", LogRecord, LOGS;
+    LogLevel = "logs/level", LogLevelMessage, SETTING;
+    Button = "debug/button", ButtonMqttMessage, BUTTON;
+    Heartbeat = "health/heartbeat", HeartbeatMessage, HEARTBEAT;
+}
+
+/// The current state, kept by the broker for boards that connect later.
+const STATE: TopicPolicy = TopicPolicy::new(QualityOfService::QoS1, true, None, None);
+/// Requests that are only acted on shortly after being sent.
+const COMMAND: TopicPolicy = TopicPolicy::new(QualityOfService::QoS1, false, None, Some(5));
+/// Up to 100 Hz, where a lost sample is soon replaced by the next one.
+const MEASUREMENT: TopicPolicy = TopicPolicy::new(QualityOfService::QoS0, false, Some(9), Some(1));
+/// Up to 50 Hz.
+const NAVIGATION: TopicPolicy = TopicPolicy::new(QualityOfService::QoS0, false, Some(18), Some(1));
+const LOGS: TopicPolicy = TopicPolicy::new(QualityOfService::QoS1, false, None, Some(600));
+const SETTING: TopicPolicy = TopicPolicy::new(QualityOfService::QoS1, false, None, None);
+/// Only useful while it is fresh, a missed beat is what the monitor looks for.
+const HEARTBEAT: TopicPolicy = TopicPolicy::new(QualityOfService::QoS0, false, None, Some(5));
+/// Up to 10 Hz.
+const BUTTON: TopicPolicy = TopicPolicy::new(QualityOfService::QoS0, false, Some(90), Some(1));
+
+// Write functions that will convert to and from the MqttTopics enum
+impl MqttTopics {
+    /// Splits a full topic into its kind, encoding and namespace.
+    pub fn from_string(topic: &str) -> Option<ParsedTopic<'_>> {
+        let (topic, content_type) = ContentType::split_topic(topic);
+        let mut levels = topic.splitn(4, '/');
+        let namespace = TopicNamespace {
+            team: levels.next()?,
+            vehicle: levels.next()?,
+            board: levels.next()?,
+        };
+        Some(ParsedTopic {
+            kind: MqttTopics::from_path(levels.next()?)?,
+            content_type,
+            namespace,
+        })
+    }
+}
+
+impl fmt::Display for MqttTopics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.path())
+    }
+}
+
+/// How the messages on one topic are published, see [`MqttTopics::policy`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopicPolicy {
+    pub qos: QualityOfService,
+    /// Whether the broker keeps the last message for new subscribers.
+    pub retain: bool,
+    /// Messages produced sooner than this after the previous one are dropped.
+    /// Set about 10% below the producer's period, so that its timer jitter
+    /// does not drop messages sent at the intended rate. Only applied to the
+    /// queued messages a [`crate::mqtt_session::MultiplexedSession`] publishes,
+    /// not to direct calls of [`crate::mqtt::HypedMqttClient::publish`].
+    pub min_interval_ms: Option<u32>,
+    /// How long the broker should hold on to a message for subscribers, sent
+    /// as the Message Expiry Interval by the host tools. rust-mqtt cannot set
+    /// properties on a PUBLISH, so messages from the boards do not expire.
+    pub expiry_secs: Option<u32>,
+}
+
+impl TopicPolicy {
+    pub const fn new(
+        qos: QualityOfService,
+        retain: bool,
+        min_interval_ms: Option<u32>,
+        expiry_secs: Option<u32>,
+    ) -> Self {
+        TopicPolicy {
+            qos,
+            retain,
+            min_interval_ms,
+            expiry_secs,
+        }
+    }
+}
+
+/// The levels in front of every topic, `team/vehicle/board`, so that several
+/// vehicles or test benches can share a broker. `board` is the sender of a
+/// message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct TopicNamespace<'a> {
+    pub team: &'a str,
+    pub vehicle: &'a str,
+    pub board: &'a str,
+}
+
+impl<'a> TopicNamespace<'a> {
+    /// The topic `kind` is published on by this board.
+    pub fn topic(
+        &self,
+        kind: MqttTopics,
+        content_type: ContentType,
+    ) -> Result<String<MAX_TOPIC_LENGTH>, Error> {
+        self.format(self.board, kind, content_type)
+    }
+
+    /// `kind` published by any board of the same vehicle.
+    pub fn any_board(
+        &self,
+        kind: MqttTopics,
+        content_type: ContentType,
+    ) -> Result<TopicFilter, TopicFilterError> {
+        let filter = self
+            .format("+", kind, content_type)
+            .map_err(|_| TopicFilterError::TooLong)?;
+        TopicFilter::new(&filter)
+    }
+
+    /// Every measurement topic of any board of the same vehicle.
+    pub fn measurements(&self) -> Result<TopicFilter, TopicFilterError> {
+        self.category("measurement")
+    }
+
+    /// Every navigation topic of any board of the same vehicle.
+    pub fn navigation(&self) -> Result<TopicFilter, TopicFilterError> {
+        self.category("navigation")
+    }
+
+    /// Everything published by any board of the same vehicle.
+    pub fn vehicle(&self) -> Result<TopicFilter, TopicFilterError> {
+        let mut filter = String::<MAX_TOPIC_LENGTH>::new();
+        write!(filter, "{}/{}/#", self.team, self.vehicle)
+            .map_err(|_| TopicFilterError::TooLong)?;
+        TopicFilter::new(&filter)
+    }
+
+    fn category(&self, category: &str) -> Result<TopicFilter, TopicFilterError> {
+        let mut filter = String::<MAX_TOPIC_LENGTH>::new();
+        write!(filter, "{}/{}/+/{}/#", self.team, self.vehicle, category)
+            .map_err(|_| TopicFilterError::TooLong)?;
+        TopicFilter::new(&filter)
+    }
+
+    fn format(
+        &self,
+        board: &str,
+        kind: MqttTopics,
+        content_type: ContentType,
+    ) -> Result<String<MAX_TOPIC_LENGTH>, Error> {
+        let mut topic = String::new();
+        write!(
+            topic,
+            "{}/{}/{}/{}{}",
+            self.team,
+            self.vehicle,
+            board,
+            kind.path(),
+            content_type.topic_suffix()
+        )
+        .map_err(|_| Error::BufferOverflow)?;
+        Ok(topic)
+    }
+}
+
+/// A topic split up by [`MqttTopics::from_string`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ParsedTopic<'t> {
+    pub kind: MqttTopics,
+    pub content_type: ContentType,
+    /// Where the message came from.
+    pub namespace: TopicNamespace<'t>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TopicFilterError {
+    Empty,
+    TooLong,
+    /// A `+` or `#` that is not a whole level, or a `#` that is not the last level.
+    InvalidWildcard,
+}
+
+/// A subscription topic filter. `+` matches one level and `#`, which must be
+/// the last level, matches any number of levels including none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicFilter {
+    filter: String<MAX_TOPIC_LENGTH>,
+}
+
+impl TopicFilter {
+    pub fn new(filter: &str) -> Result<Self, TopicFilterError> {
+        if filter.is_empty() {
+            return Err(TopicFilterError::Empty);
+        }
+        let mut levels = filter.split('/').peekable();
+        while let Some(level) = levels.next() {
+            let valid = match level {
+                "+" => true,
+                "#" => levels.peek().is_none(),
+                level => !level.contains(['+', '#']),
+            };
+            if !valid {
+                return Err(TopicFilterError::InvalidWildcard);
             }
-            MqttTopics::Acceleration => {
-                String::<48>::from_str("hyped/cart_2024/navigation/acceleration").unwrap()
+        }
+        Ok(TopicFilter {
+            filter: String::try_from(filter).map_err(|_| TopicFilterError::TooLong)?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.filter.as_str()
+    }
+
+    /// Whether a message published on `topic` is delivered to this filter.
+    /// Filters starting with a wildcard never match `$` topics, such as
+    /// `$SYS/...`.
+    pub fn matches(&self, topic: &str) -> bool {
+        if topic.starts_with('$') && self.filter.starts_with(['+', '#']) {
+            return false;
+        }
+        let mut filter = self.filter.split('/');
+        let mut topic = topic.split('/');
+        loop {
+            match (filter.next(), topic.next()) {
+                (Some("#"), _) => return true,
+                (Some("+"), Some(_)) => {}
+                (Some(expected), Some(level)) if expected == level => {}
+                (None, None) => return true,
+                _ => return false,
             }
-            MqttTopics::Logs => String::<48>::from_str("hyped/cart_2024/logs").unwrap(),
         }
     }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl defmt::Format for TopicFilter {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MqttTopics, ParsedTopic, TopicFilter, TopicFilterError, TopicNamespace};
+    use crate::codec::ContentType;
+    use crate::mqtt_config::MqttConfig;
+
+    fn matches(filter: &str, topic: &str) -> bool {
+        TopicFilter::new(filter).unwrap().matches(topic)
+    }
 
-    #[cfg(feature = "std")]
-    pub fn to_string(&self) -> String {
-        match self {
-            MqttTopics::State => "hyped/cart_2024/state/state".to_string(),
-            MqttTopics::StateRequest => "hyped/cart_2024/state/state_request".to_string(),
-            MqttTopics::Accelerometer => "hyped/cart_2024/measurement/accelerometer".to_string(),
-            MqttTopics::OpticalFlow => "hyped/cart_2024/measurement/optical_flow".to_string(),
-            MqttTopics::Keyence => "hyped/cart_2024/measurement/keyence".to_string(),
-            MqttTopics::Displacement => "hyped/cart_2024/navigation/displacement".to_string(),
-            MqttTopics::Velocity => "hyped/cart_2024/navigation/velocity".to_string(),
-            MqttTopics::Acceleration => "hyped/cart_2024/navigation/acceleration".to_string(),
-            MqttTopics::Logs => "hyped/cart_2024/logs".to_string(),
+    #[test]
+    fn invalid_filters_are_rejected() {
+        for filter in ["a/b#", "#/a", "a/#/b", "a+", "a/+b/c", "+#", "##"] {
+            assert_eq!(
+                TopicFilter::new(filter),
+                Err(TopicFilterError::InvalidWildcard),
+                "{}",
+                filter
+            );
         }
+        assert_eq!(TopicFilter::new(""), Err(TopicFilterError::Empty));
+        assert_eq!(
+            TopicFilter::new(&"a".repeat(super::MAX_TOPIC_LENGTH + 1)),
+            Err(TopicFilterError::TooLong)
+        );
+        for filter in ["#", "+", "+/+", "a/#", "/", "a//b", "+/#"] {
+            assert!(TopicFilter::new(filter).is_ok(), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn exact_filters_match_only_the_same_topic() {
+        assert!(matches("a/b", "a/b"));
+        assert!(!matches("a/b", "a"));
+        assert!(!matches("a/b", "a/b/c"));
+        assert!(!matches("a/b", "a/b/"));
+        assert!(!matches("a/b", "A/b"));
+        assert!(matches("/a", "/a"));
+        assert!(!matches("/a", "a"));
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        assert!(matches("#", "a"));
+        assert!(matches("#", "a/b/c"));
+        assert!(matches("#", "/"));
+        assert!(matches("a/#", "a"));
+        assert!(matches("a/#", "a/"));
+        assert!(matches("a/#", "a/b/c"));
+        assert!(!matches("a/#", "b"));
+        assert!(!matches("a/#", "ab"));
+        assert!(matches("+/#", "a"));
     }
 
-    pub fn from_string(topic: &str) -> Option<MqttTopics> {
-        match topic {
-            "hyped/cart_2024/state/state" => Some(MqttTopics::State),
-            "hyped/cart_2024/state/state_request" => Some(MqttTopics::StateRequest),
-            "hyped/cart_2024/measurement/accelerometer" => Some(MqttTopics::Accelerometer),
-            "hyped/cart_2024/measurement/optical_flow" => Some(MqttTopics::OpticalFlow),
-            "hyped/cart_2024/measurement/keyence" => Some(MqttTopics::Keyence),
-            "hyped/cart_2024/navigation/displacement" => Some(MqttTopics::Displacement),
-            "hyped/cart_2024/navigation/velocity" => Some(MqttTopics::Velocity),
-            "hyped/cart_2024/navigation/acceleration" => Some(MqttTopics::Acceleration),
-            "hyped/cart_2024/logs" => Some(MqttTopics::Logs),
-            _ => None,
+    #[test]
+    fn single_level_wildcard() {
+        assert!(matches("+", "a"));
+        assert!(!matches("+", "a/b"));
+        assert!(!matches("+", "/"));
+        assert!(matches("+/+", "/"));
+        assert!(matches("a/+/c", "a//c"));
+        assert!(matches("a/+", "a/"));
+        assert!(!matches("a/+", "a"));
+        assert!(matches("+/b", "/b"));
+        assert!(!matches("a/+/c", "a/b/d"));
+    }
+
+    #[test]
+    fn dollar_topics_only_match_explicit_filters() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(!matches("+/#", "$SYS"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+        assert!(matches("a/+", "a/$b"));
+    }
+
+    #[test]
+    fn every_topic_round_trips() {
+        let namespaces = [
+            MqttConfig::DEFAULT.namespace,
+            TopicNamespace {
+                team: "team",
+                vehicle: "bench-2",
+                board: "base_station",
+            },
+        ];
+        for namespace in namespaces {
+            for kind in MqttTopics::ALL {
+                assert_eq!(MqttTopics::from_path(kind.path()), Some(kind));
+                for content_type in ContentType::ALL {
+                    let topic = namespace.topic(kind, content_type).unwrap();
+                    assert_eq!(
+                        MqttTopics::from_string(&topic),
+                        Some(ParsedTopic {
+                            kind,
+                            content_type,
+                            namespace,
+                        }),
+                        "{}",
+                        topic
+                    );
+                    let filter = namespace.any_board(kind, content_type).unwrap();
+                    assert!(filter.matches(&topic), "{} {}", filter, topic);
+                }
+            }
         }
     }
 }
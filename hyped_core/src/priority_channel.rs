@@ -0,0 +1,180 @@
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    channel::{Channel, TrySendError},
+};
+
+use crate::{mqtt::MqttMessage, mqtt_topics::MqttTopics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Priority {
+    /// Emergency, state and heartbeat traffic, always sent before anything
+    /// queued as `Normal`, so that a busy queue does not make a board look lost.
+    High,
+    Normal,
+}
+
+impl Priority {
+    pub fn of(topic: MqttTopics) -> Priority {
+        match topic {
+            MqttTopics::State | MqttTopics::StateRequest | MqttTopics::Heartbeat => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+impl MqttMessage {
+    pub fn priority(&self) -> Priority {
+        Priority::of(self.kind)
+    }
+}
+
+/// A queue with two lanes. Receivers always take from the high lane while it
+/// has messages, and a full normal lane never blocks high priority senders.
+pub struct PriorityChannel<M: RawMutex, T, const HIGH: usize, const NORMAL: usize> {
+    high: Channel<M, T, HIGH>,
+    normal: Channel<M, T, NORMAL>,
+}
+
+impl<M: RawMutex, T, const HIGH: usize, const NORMAL: usize> PriorityChannel<M, T, HIGH, NORMAL> {
+    pub const fn new() -> Self {
+        PriorityChannel {
+            high: Channel::new(),
+            normal: Channel::new(),
+        }
+    }
+
+    /// Waits for space in the lane for `priority`.
+    pub async fn send(&self, message: T, priority: Priority) {
+        match priority {
+            Priority::High => self.high.send(message).await,
+            Priority::Normal => self.normal.send(message).await,
+        }
+    }
+
+    pub fn try_send(&self, message: T, priority: Priority) -> Result<(), TrySendError<T>> {
+        match priority {
+            Priority::High => self.high.try_send(message),
+            Priority::Normal => self.normal.try_send(message),
+        }
+    }
+
+    pub async fn receive(&self) -> T {
+        if let Some(message) = self.try_receive() {
+            return message;
+        }
+        // If both lanes become ready together `select` polls the high lane first.
+        match select(self.high.receive(), self.normal.receive()).await {
+            Either::First(message) | Either::Second(message) => message,
+        }
+    }
+
+    pub fn try_receive(&self) -> Option<T> {
+        self.high
+            .try_receive()
+            .or_else(|_| self.normal.try_receive())
+            .ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+}
+
+impl<M: RawMutex, T, const HIGH: usize, const NORMAL: usize> Default
+    for PriorityChannel<M, T, HIGH, NORMAL>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, join::join};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::{Priority, PriorityChannel};
+    use crate::{
+        mqtt::{ButtonMqttMessage, MqttMessage},
+        mqtt_topics::MqttTopics,
+    };
+
+    type Queue = PriorityChannel<NoopRawMutex, u32, 4, 128>;
+
+    fn flooded() -> Queue {
+        let queue = Queue::new();
+        for sample in 0..128 {
+            queue.try_send(sample, Priority::Normal).unwrap();
+        }
+        assert!(queue.try_send(128, Priority::Normal).is_err());
+        queue
+    }
+
+    #[test]
+    fn full_normal_lane_does_not_block_high() {
+        let queue = flooded();
+        queue.try_send(1000, Priority::High).unwrap();
+        block_on(queue.send(1001, Priority::High));
+        assert_eq!(queue.len(), 130);
+    }
+
+    #[test]
+    fn try_receive_takes_high_first() {
+        let queue = flooded();
+        queue.try_send(1000, Priority::High).unwrap();
+        queue.try_send(1001, Priority::High).unwrap();
+        assert_eq!(queue.try_receive(), Some(1000));
+        assert_eq!(queue.try_receive(), Some(1001));
+        for sample in 0..128 {
+            assert_eq!(queue.try_receive(), Some(sample));
+        }
+        assert_eq!(queue.try_receive(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn receive_takes_high_first() {
+        let queue = flooded();
+        assert_eq!(block_on(queue.receive()), 0);
+        queue.try_send(1000, Priority::High).unwrap();
+        assert_eq!(block_on(queue.receive()), 1000);
+        assert_eq!(block_on(queue.receive()), 1);
+    }
+
+    #[test]
+    fn receive_waits_for_either_lane() {
+        let queue = Queue::new();
+        let (received, _) = block_on(join(queue.receive(), async {
+            queue.send(7, Priority::High).await;
+        }));
+        assert_eq!(received, 7);
+        let (received, _) = block_on(join(queue.receive(), async {
+            queue.send(8, Priority::Normal).await;
+        }));
+        assert_eq!(received, 8);
+    }
+
+    #[test]
+    fn state_and_heartbeat_topics_are_high_priority() {
+        for topic in MqttTopics::ALL {
+            let expected = match topic {
+                MqttTopics::State | MqttTopics::StateRequest | MqttTopics::Heartbeat => {
+                    Priority::High
+                }
+                _ => Priority::Normal,
+            };
+            assert_eq!(Priority::of(topic), expected);
+        }
+        let button = ButtonMqttMessage {
+            task_id: 1,
+            status: true,
+        };
+        let message = MqttMessage::from_message(&button).unwrap();
+        assert_eq!(message.priority(), Priority::Normal);
+    }
+}
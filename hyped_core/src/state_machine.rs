@@ -0,0 +1,199 @@
+use defmt::*;
+use serde::{Deserialize, Serialize};
+
+use crate::mqtt_messages::{StateMessage, StateRequestMessage};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PodState {
+    Idle,
+    Calibrating,
+    Ready,
+    Accelerating,
+    Braking,
+    Stopped,
+    Emergency,
+}
+
+/// Every allowed `(from, to)` pair apart from entering `Emergency`, which is
+/// allowed from any other state.
+const TRANSITIONS: &[(PodState, PodState)] = &[
+    (PodState::Idle, PodState::Calibrating),
+    (PodState::Calibrating, PodState::Ready),
+    (PodState::Calibrating, PodState::Idle),
+    (PodState::Ready, PodState::Accelerating),
+    (PodState::Ready, PodState::Idle),
+    (PodState::Accelerating, PodState::Braking),
+    (PodState::Braking, PodState::Stopped),
+    (PodState::Stopped, PodState::Idle),
+    (PodState::Emergency, PodState::Idle),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct TransitionError {
+    pub from: PodState,
+    pub to: PodState,
+}
+
+pub fn is_valid_transition(from: PodState, to: PodState) -> bool {
+    (to == PodState::Emergency && from != PodState::Emergency) || TRANSITIONS.contains(&(from, to))
+}
+
+pub struct StateMachine {
+    current: PodState,
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateMachine {
+    pub const fn new() -> Self {
+        StateMachine {
+            current: PodState::Idle,
+        }
+    }
+
+    pub fn current(&self) -> PodState {
+        self.current
+    }
+
+    /// Moves to `to` if the transition table allows it, returning the message
+    /// to publish on the State topic.
+    pub fn transition(
+        &mut self,
+        to: PodState,
+        timestamp_ms: u64,
+    ) -> Result<StateMessage, TransitionError> {
+        let from = self.current;
+        if !is_valid_transition(from, to) {
+            return Err(TransitionError { from, to });
+        }
+        self.current = to;
+        Ok(StateMessage {
+            previous: from,
+            current: to,
+            timestamp_ms,
+        })
+    }
+
+    pub fn handle_request(
+        &mut self,
+        request: &StateRequestMessage,
+        timestamp_ms: u64,
+    ) -> Result<StateMessage, TransitionError> {
+        self.transition(request.requested, timestamp_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_transition, PodState, StateMachine, TransitionError};
+    use crate::mqtt_messages::StateRequestMessage;
+
+    const STATES: [PodState; 7] = [
+        PodState::Idle,
+        PodState::Calibrating,
+        PodState::Ready,
+        PodState::Accelerating,
+        PodState::Braking,
+        PodState::Stopped,
+        PodState::Emergency,
+    ];
+
+    /// Written out again rather than taken from `TRANSITIONS`, so that a
+    /// change to the table has to be made in both places.
+    const ALLOWED: [(PodState, PodState); 9] = [
+        (PodState::Idle, PodState::Calibrating),
+        (PodState::Calibrating, PodState::Ready),
+        (PodState::Calibrating, PodState::Idle),
+        (PodState::Ready, PodState::Accelerating),
+        (PodState::Ready, PodState::Idle),
+        (PodState::Accelerating, PodState::Braking),
+        (PodState::Braking, PodState::Stopped),
+        (PodState::Stopped, PodState::Idle),
+        (PodState::Emergency, PodState::Idle),
+    ];
+
+    #[test]
+    fn every_pair() {
+        for from in STATES {
+            for to in STATES {
+                let expected = ALLOWED.contains(&(from, to))
+                    || (to == PodState::Emergency && from != PodState::Emergency);
+                assert_eq!(
+                    is_valid_transition(from, to),
+                    expected,
+                    "{from:?} -> {to:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn emergency_from_every_state() {
+        for from in STATES {
+            let mut machine = StateMachine { current: from };
+            let result = machine.transition(PodState::Emergency, 7);
+            if from == PodState::Emergency {
+                assert_eq!(
+                    result.unwrap_err(),
+                    TransitionError {
+                        from,
+                        to: PodState::Emergency
+                    }
+                );
+            } else {
+                let message = result.unwrap();
+                assert_eq!(message.previous, from);
+                assert_eq!(message.current, PodState::Emergency);
+                assert_eq!(message.timestamp_ms, 7);
+            }
+            assert_eq!(machine.current(), PodState::Emergency);
+        }
+    }
+
+    #[test]
+    fn rejected_transition_keeps_state() {
+        let mut machine = StateMachine::new();
+        let error = machine.transition(PodState::Accelerating, 0).unwrap_err();
+        assert_eq!(
+            error,
+            TransitionError {
+                from: PodState::Idle,
+                to: PodState::Accelerating
+            }
+        );
+        assert_eq!(machine.current(), PodState::Idle);
+    }
+
+    #[test]
+    fn handle_request_runs_a_whole_trip() {
+        let mut machine = StateMachine::default();
+        let trip = [
+            PodState::Calibrating,
+            PodState::Ready,
+            PodState::Accelerating,
+            PodState::Braking,
+            PodState::Stopped,
+            PodState::Idle,
+        ];
+        for (timestamp_ms, requested) in (0..).zip(trip) {
+            let previous = machine.current();
+            let message = machine
+                .handle_request(&StateRequestMessage { requested }, timestamp_ms)
+                .unwrap();
+            assert_eq!(message.previous, previous);
+            assert_eq!(message.current, requested);
+            assert_eq!(message.timestamp_ms, timestamp_ms);
+            assert_eq!(machine.current(), requested);
+        }
+
+        let request = StateRequestMessage {
+            requested: PodState::Braking,
+        };
+        assert!(machine.handle_request(&request, 10).is_err());
+        assert_eq!(machine.current(), PodState::Idle);
+    }
+}
@@ -0,0 +1,23 @@
+//! defmt global logger for host builds. The host has no probe to decode defmt
+//! frames, so everything logged through defmt is discarded. Without it, any
+//! host binary or test that calls into this crate fails to link.
+
+#[defmt::global_logger]
+struct DiscardLogger;
+
+unsafe impl defmt::Logger for DiscardLogger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u64}", 0);
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
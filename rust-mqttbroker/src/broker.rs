@@ -0,0 +1,838 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::packet::{self, Connect, Packet, ProtocolVersion, Publish, SubscriptionOptions};
+use crate::topic;
+
+/// Time a new connection has to send its CONNECT packet.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Idle limit for clients that disabled keep alive.
+const NO_KEEP_ALIVE: Duration = Duration::from_secs(24 * 60 * 60);
+
+enum Outgoing {
+    Packet(Vec<u8>),
+    /// Another connection took over the client id.
+    Close,
+}
+
+struct Subscription {
+    filter: String,
+    options: SubscriptionOptions,
+}
+
+struct Session {
+    connection: u64,
+    version: ProtocolVersion,
+    sender: UnboundedSender<Outgoing>,
+    subscriptions: Vec<Subscription>,
+    next_packet_id: u16,
+}
+
+impl Session {
+    fn deliver(&mut self, message: &Publish, qos: u8, retain: bool) {
+        let packet_id = if qos > 0 {
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            Some(self.next_packet_id)
+        } else {
+            None
+        };
+        let delivery = Publish {
+            topic: message.topic.clone(),
+            payload: message.payload.clone(),
+            qos,
+            retain,
+            packet_id,
+            properties: message.properties.clone(),
+        };
+        let _ = self
+            .sender
+            .send(Outgoing::Packet(packet::publish(self.version, &delivery)));
+    }
+}
+
+struct Retained {
+    message: Publish,
+    received: Instant,
+}
+
+impl Retained {
+    /// Seconds left of the message expiry interval, if the publisher set one.
+    fn remaining_expiry(&self) -> Option<u32> {
+        let stored = self.received.elapsed().as_secs();
+        self.message
+            .properties
+            .message_expiry_secs
+            .map(|expiry| u64::from(expiry).saturating_sub(stored) as u32)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_connection: u64,
+    sessions: HashMap<String, Session>,
+    retained: BTreeMap<String, Retained>,
+}
+
+/// Routes messages between connected clients. Sessions are not persisted:
+/// every connection starts clean and QoS 1 deliveries are not retried.
+pub struct Broker {
+    state: Mutex<State>,
+    allow_anonymous: bool,
+    verbose: bool,
+}
+
+enum Flow {
+    Continue,
+    /// The client sent DISCONNECT, so its will is discarded.
+    Disconnect,
+    Drop(String),
+}
+
+struct Connection {
+    client_id: String,
+    connection: u64,
+    version: ProtocolVersion,
+    keep_alive: Option<Duration>,
+    will: Option<Publish>,
+}
+
+impl Broker {
+    pub fn new(allow_anonymous: bool, verbose: bool) -> Self {
+        Broker {
+            state: Mutex::new(State::default()),
+            allow_anonymous,
+            verbose,
+        }
+    }
+
+    pub async fn handle(self: Arc<Self>, mut stream: TcpStream, peer: SocketAddr) {
+        let (sender, mut receiver) = unbounded_channel();
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 4096];
+        let mut connection: Option<Connection> = None;
+        let mut deadline = Instant::now() + CONNECT_TIMEOUT;
+
+        let reason = 'connection: loop {
+            tokio::select! {
+                read = stream.read(&mut chunk) => {
+                    let count = match read {
+                        Ok(0) => break 'connection "connection closed by client".to_string(),
+                        Ok(count) => count,
+                        Err(err) => break 'connection err.to_string(),
+                    };
+                    buffer.extend_from_slice(&chunk[..count]);
+                    loop {
+                        let version = connection.as_ref().map(|connection| connection.version);
+                        let (packet, used) = match packet::decode(&buffer, version) {
+                            Ok(Some(decoded)) => decoded,
+                            Ok(None) => break,
+                            Err(err) => {
+                                if let packet::DecodeError::UnsupportedVersion(_) = err {
+                                    let _ = stream
+                                        .write_all(&packet::connack(ProtocolVersion::V311, 0x01, None))
+                                        .await;
+                                }
+                                break 'connection format!("{:?}", err);
+                            }
+                        };
+                        buffer.drain(..used);
+                        match self.handle_packet(packet, &mut connection, &sender, peer) {
+                            Flow::Continue => {}
+                            Flow::Disconnect => {
+                                if let Some(connection) = connection.as_mut() {
+                                    connection.will = None;
+                                }
+                                break 'connection "disconnected".to_string();
+                            }
+                            Flow::Drop(reason) => break 'connection reason,
+                        }
+                    }
+                    deadline = match connection.as_ref() {
+                        Some(Connection { keep_alive: Some(keep_alive), .. }) => {
+                            Instant::now() + *keep_alive
+                        }
+                        Some(_) => Instant::now() + NO_KEEP_ALIVE,
+                        None => deadline,
+                    };
+                }
+                Some(outgoing) = receiver.recv() => match outgoing {
+                    Outgoing::Packet(bytes) => {
+                        if let Err(err) = stream.write_all(&bytes).await {
+                            break 'connection err.to_string();
+                        }
+                    }
+                    Outgoing::Close => {
+                        if let Some(connection) = connection.as_mut() {
+                            connection.will = None;
+                        }
+                        break 'connection "session taken over".to_string();
+                    }
+                },
+                _ = sleep_until(deadline) => break 'connection "keep alive timeout".to_string(),
+            }
+        };
+
+        // Flush anything queued for this client, such as the CONNACK of a rejected connection.
+        while let Ok(Outgoing::Packet(bytes)) = receiver.try_recv() {
+            let _ = stream.write_all(&bytes).await;
+        }
+
+        if let Some(connection) = connection {
+            println!(
+                "{} ({}) disconnected: {}",
+                connection.client_id, peer, reason
+            );
+            self.close_session(&connection.client_id, connection.connection);
+            if let Some(will) = connection.will {
+                self.publish(&will, None);
+            }
+        } else {
+            println!("{} dropped before CONNECT: {}", peer, reason);
+        }
+    }
+
+    fn handle_packet(
+        &self,
+        packet: Packet,
+        connection: &mut Option<Connection>,
+        sender: &UnboundedSender<Outgoing>,
+        peer: SocketAddr,
+    ) -> Flow {
+        let Some(current) = connection.as_ref() else {
+            return match packet {
+                Packet::Connect(connect) => self.connect(connect, connection, sender, peer),
+                _ => Flow::Drop("expected CONNECT".to_string()),
+            };
+        };
+        let version = current.version;
+        let reply = |bytes: Vec<u8>| {
+            let _ = sender.send(Outgoing::Packet(bytes));
+        };
+
+        match packet {
+            Packet::Connect(_) => Flow::Drop("second CONNECT".to_string()),
+            Packet::Publish(message) => {
+                if message.qos > 1 {
+                    return Flow::Drop("QoS 2 is not supported".to_string());
+                }
+                if !topic::is_valid_name(&message.topic) {
+                    return Flow::Drop(format!("invalid topic name {:?}", message.topic));
+                }
+                if let Some(packet_id) = message.packet_id {
+                    reply(packet::puback(version, packet_id));
+                }
+                if self.verbose {
+                    println!(
+                        "{} -> {} ({} bytes, QoS {}{})",
+                        current.client_id,
+                        message.topic,
+                        message.payload.len(),
+                        message.qos,
+                        if message.retain { ", retained" } else { "" }
+                    );
+                }
+                self.publish(&message, Some(&current.client_id));
+                Flow::Continue
+            }
+            Packet::Puback => Flow::Continue,
+            Packet::Subscribe { packet_id, filters } => {
+                let reasons = self.subscribe(&current.client_id, &filters);
+                reply(packet::suback(version, packet_id, &reasons));
+                self.send_retained(&current.client_id, &filters);
+                Flow::Continue
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                let reasons = self.unsubscribe(&current.client_id, &filters);
+                reply(packet::unsuback(version, packet_id, &reasons));
+                Flow::Continue
+            }
+            Packet::Pingreq => {
+                reply(packet::pingresp());
+                Flow::Continue
+            }
+            Packet::Disconnect => Flow::Disconnect,
+        }
+    }
+
+    fn connect(
+        &self,
+        connect: Connect,
+        connection: &mut Option<Connection>,
+        sender: &UnboundedSender<Outgoing>,
+        peer: SocketAddr,
+    ) -> Flow {
+        let version = connect.version;
+        // There is no way to check passwords, so without anonymous access no
+        // client can be authenticated.
+        if !self.allow_anonymous {
+            let reason = match version {
+                ProtocolVersion::V311 => 0x05,
+                ProtocolVersion::V5 => 0x87,
+            };
+            let _ = sender.send(Outgoing::Packet(packet::connack(version, reason, None)));
+            return Flow::Drop(match connect.username {
+                Some(username) => format!("cannot check the password of {}", username),
+                None => "anonymous connections are not allowed".to_string(),
+            });
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.next_connection += 1;
+        let id = state.next_connection;
+
+        let assigned = connect.client_id.is_empty();
+        let client_id = if assigned {
+            format!("auto-{}", id)
+        } else {
+            connect.client_id
+        };
+        if let Some(previous) = state.sessions.remove(&client_id) {
+            let _ = previous.sender.send(Outgoing::Close);
+        }
+        state.sessions.insert(
+            client_id.clone(),
+            Session {
+                connection: id,
+                version,
+                sender: sender.clone(),
+                subscriptions: Vec::new(),
+                next_packet_id: 0,
+            },
+        );
+
+        let assigned_client_id = match (assigned, version) {
+            (true, ProtocolVersion::V5) => Some(client_id.as_str()),
+            _ => None,
+        };
+        let _ = sender.send(Outgoing::Packet(packet::connack(
+            version,
+            0x00,
+            assigned_client_id,
+        )));
+        println!(
+            "{} ({}) connected using MQTT {:?}",
+            client_id, peer, version
+        );
+
+        *connection = Some(Connection {
+            client_id,
+            connection: id,
+            version,
+            keep_alive: match connect.keep_alive {
+                0 => None,
+                seconds => Some(Duration::from_millis(seconds as u64 * 1500)),
+            },
+            will: connect.will,
+        });
+        Flow::Continue
+    }
+
+    fn close_session(&self, client_id: &str, connection: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .sessions
+            .get(client_id)
+            .is_some_and(|session| session.connection == connection)
+        {
+            state.sessions.remove(client_id);
+        }
+    }
+
+    fn subscribe(&self, client_id: &str, filters: &[(String, SubscriptionOptions)]) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        let Some(session) = state.sessions.get_mut(client_id) else {
+            return Vec::new();
+        };
+        filters
+            .iter()
+            .map(|(filter, options)| {
+                if !topic::is_valid_filter(filter) {
+                    println!("{} sent invalid topic filter {:?}", client_id, filter);
+                    return match session.version {
+                        ProtocolVersion::V311 => 0x80,
+                        ProtocolVersion::V5 => 0x8F,
+                    };
+                }
+                let options = SubscriptionOptions {
+                    qos: options.qos.min(1),
+                    ..*options
+                };
+                session
+                    .subscriptions
+                    .retain(|subscription| subscription.filter != *filter);
+                session.subscriptions.push(Subscription {
+                    filter: filter.clone(),
+                    options,
+                });
+                println!("{} subscribed to {}", client_id, filter);
+                options.qos
+            })
+            .collect()
+    }
+
+    fn unsubscribe(&self, client_id: &str, filters: &[String]) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        let Some(session) = state.sessions.get_mut(client_id) else {
+            return Vec::new();
+        };
+        filters
+            .iter()
+            .map(|filter| {
+                let before = session.subscriptions.len();
+                session
+                    .subscriptions
+                    .retain(|subscription| subscription.filter != *filter);
+                if session.subscriptions.len() < before {
+                    0x00
+                } else {
+                    0x11
+                }
+            })
+            .collect()
+    }
+
+    fn send_retained(&self, client_id: &str, filters: &[(String, SubscriptionOptions)]) {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            sessions, retained, ..
+        } = &mut *state;
+        let Some(session) = sessions.get_mut(client_id) else {
+            return;
+        };
+        retained.retain(|_, stored| stored.remaining_expiry() != Some(0));
+        for stored in retained.values() {
+            let granted = filters
+                .iter()
+                .filter(|(filter, _)| topic::matches(filter, &stored.message.topic))
+                .map(|(_, options)| options.qos.min(1))
+                .max();
+            let Some(qos) = granted else {
+                continue;
+            };
+            // Subscribers are sent the time the message has left.
+            let mut message = stored.message.clone();
+            message.properties.message_expiry_secs = stored.remaining_expiry();
+            session.deliver(&message, qos.min(message.qos), true);
+        }
+    }
+
+    /// Stores retained messages and forwards `message` to every matching
+    /// subscription. `sender` is the publishing client, if any.
+    fn publish(&self, message: &Publish, sender: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        if message.retain {
+            if message.payload.is_empty() {
+                state.retained.remove(&message.topic);
+            } else {
+                let stored = Retained {
+                    message: message.clone(),
+                    received: Instant::now(),
+                };
+                state.retained.insert(message.topic.clone(), stored);
+            }
+        }
+
+        for (client_id, session) in state.sessions.iter_mut() {
+            let granted = session
+                .subscriptions
+                .iter()
+                .filter(|subscription| topic::matches(&subscription.filter, &message.topic))
+                .filter(|subscription| {
+                    !(subscription.options.no_local && Some(client_id.as_str()) == sender)
+                })
+                .map(|subscription| subscription.options.qos)
+                .max();
+            if let Some(qos) = granted {
+                session.deliver(message, qos.min(message.qos), false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{timeout, Duration};
+
+    use super::Broker;
+    use crate::packet::{self, Packet, ProtocolVersion, Publish, PublishProperties};
+
+    /// How long a client waits for a packet that should arrive.
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+    /// How long a client waits to be sure that nothing arrives.
+    const QUIET_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Runs a broker on a free local port, as `main` does for each listener.
+    async fn start(allow_anonymous: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(crate::accept(
+            listener,
+            Arc::new(Broker::new(allow_anonymous, false)),
+        ));
+        address
+    }
+
+    fn push_string(body: &mut Vec<u8>, value: &str) {
+        body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        body.extend_from_slice(value.as_bytes());
+    }
+
+    /// A minimal MQTT 3.1.1 or 5 client writing its packets by hand.
+    struct TestClient {
+        stream: TcpStream,
+        version: ProtocolVersion,
+        buffer: Vec<u8>,
+        next_packet_id: u16,
+    }
+
+    impl TestClient {
+        /// Connects over MQTT 3.1.1 with a clean session and returns the
+        /// CONNACK reason code.
+        async fn connect(
+            address: SocketAddr,
+            client_id: &str,
+            will: Option<(&str, &[u8])>,
+        ) -> (TestClient, u8) {
+            TestClient::connect_with(address, ProtocolVersion::V311, client_id, will).await
+        }
+
+        async fn connect_with(
+            address: SocketAddr,
+            version: ProtocolVersion,
+            client_id: &str,
+            will: Option<(&str, &[u8])>,
+        ) -> (TestClient, u8) {
+            let mut client = TestClient {
+                stream: TcpStream::connect(address).await.unwrap(),
+                version,
+                buffer: Vec::new(),
+                next_packet_id: 0,
+            };
+            let is_v5 = version == ProtocolVersion::V5;
+            let mut body = Vec::new();
+            push_string(&mut body, "MQTT");
+            body.push(if is_v5 { 5 } else { 4 });
+            body.push(0x02 | if will.is_some() { 0x04 } else { 0 });
+            body.extend_from_slice(&60u16.to_be_bytes());
+            if is_v5 {
+                body.push(0);
+            }
+            push_string(&mut body, client_id);
+            if is_v5 && will.is_some() {
+                body.push(0);
+            }
+            if let Some((topic, payload)) = will {
+                push_string(&mut body, topic);
+                body.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                body.extend_from_slice(payload);
+            }
+            client.send(0x10, &body).await;
+            let (header, body) = client.read_packet().await.unwrap();
+            assert_eq!(header, 0x20);
+            (client, body[1])
+        }
+
+        async fn send(&mut self, header: u8, body: &[u8]) {
+            self.stream.write_all(&encode(header, body)).await.unwrap();
+        }
+
+        /// The next packet as its fixed header byte and body, or `None` if the
+        /// broker closed the connection.
+        async fn read_packet(&mut self) -> Option<(u8, Vec<u8>)> {
+            loop {
+                if let Some((header, start, end)) = split_packet(&self.buffer) {
+                    let packet: Vec<u8> = self.buffer.drain(..end).collect();
+                    return Some((header, packet[start..].to_vec()));
+                }
+                let mut chunk = [0; 1024];
+                let count = timeout(RECEIVE_TIMEOUT, self.stream.read(&mut chunk))
+                    .await
+                    .expect("no packet from the broker")
+                    .unwrap();
+                if count == 0 {
+                    return None;
+                }
+                self.buffer.extend_from_slice(&chunk[..count]);
+            }
+        }
+
+        /// Subscribes to `filter` and returns the granted QoS.
+        async fn subscribe(&mut self, filter: &str, qos: u8) -> u8 {
+            self.next_packet_id += 1;
+            let mut body = self.next_packet_id.to_be_bytes().to_vec();
+            let is_v5 = self.version == ProtocolVersion::V5;
+            if is_v5 {
+                body.push(0);
+            }
+            push_string(&mut body, filter);
+            body.push(qos);
+            self.send(0x82, &body).await;
+            let (header, body) = self.read_packet().await.unwrap();
+            assert_eq!(header, 0x90);
+            body[if is_v5 { 3 } else { 2 }]
+        }
+
+        /// Publishes and, for QoS 1, waits for the PUBACK, so that the broker
+        /// has routed the message when this returns.
+        async fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) {
+            self.publish_message(Publish {
+                topic: topic.to_string(),
+                payload: payload.to_vec(),
+                qos,
+                retain,
+                ..Publish::default()
+            })
+            .await;
+        }
+
+        /// As `publish`, with the packet id filled in.
+        async fn publish_message(&mut self, mut message: Publish) {
+            let qos = message.qos;
+            message.packet_id = (qos > 0).then(|| {
+                self.next_packet_id += 1;
+                self.next_packet_id
+            });
+            let bytes = packet::publish(self.version, &message);
+            self.stream.write_all(&bytes).await.unwrap();
+            if qos > 0 {
+                let (header, _) = self.read_packet().await.unwrap();
+                assert_eq!(header, 0x40);
+            }
+        }
+
+        async fn disconnect(mut self) {
+            self.send(0xE0, &[]).await;
+        }
+
+        async fn receive(&mut self) -> Publish {
+            let (header, body) = self.read_packet().await.expect("connection closed");
+            let bytes = encode(header, &body);
+            match packet::decode(&bytes, Some(self.version)) {
+                Ok(Some((Packet::Publish(message), _))) => message,
+                other => panic!("expected a PUBLISH, got {:?}", other),
+            }
+        }
+
+        async fn assert_nothing_received(&mut self) {
+            let mut chunk = [0; 1024];
+            if let Ok(read) = timeout(QUIET_TIMEOUT, self.stream.read(&mut chunk)).await {
+                panic!("unexpected {:?}", &chunk[..read.unwrap()]);
+            }
+        }
+    }
+
+    fn encode(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![header];
+        let mut remaining = body.len();
+        loop {
+            let byte = (remaining % 128) as u8;
+            remaining /= 128;
+            bytes.push(if remaining > 0 { byte | 0x80 } else { byte });
+            if remaining == 0 {
+                break;
+            }
+        }
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// Returns the fixed header byte and the bounds of the body of the first
+    /// complete packet in `data`.
+    fn split_packet(data: &[u8]) -> Option<(u8, usize, usize)> {
+        let mut length = 0;
+        for (index, byte) in data.iter().skip(1).take(4).enumerate() {
+            length |= usize::from(byte & 0x7F) << (7 * index);
+            if byte & 0x80 == 0 {
+                let start = index + 2;
+                return (data.len() >= start + length).then_some((data[0], start, start + length));
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn routes_to_matching_subscribers() {
+        let address = start(true).await;
+        let (mut state, _) = TestClient::connect(address, "state", None).await;
+        let (mut logs, _) = TestClient::connect(address, "logs", None).await;
+        let (mut sender, _) = TestClient::connect(address, "sender", None).await;
+        state.subscribe("hyped/cart_2024/state", 0).await;
+        logs.subscribe("hyped/cart_2024/logs", 0).await;
+
+        sender
+            .publish("hyped/cart_2024/state", b"idle", 1, false)
+            .await;
+        let message = state.receive().await;
+        assert_eq!(message.topic, "hyped/cart_2024/state");
+        assert_eq!(message.payload, b"idle");
+        assert!(!message.retain);
+        logs.assert_nothing_received().await;
+        sender.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn delivers_retained_messages_on_subscribe() {
+        let address = start(true).await;
+        let (mut sender, _) = TestClient::connect(address, "sender", None).await;
+        sender
+            .publish("hyped/cart_2024/state", b"old", 1, true)
+            .await;
+        sender
+            .publish("hyped/cart_2024/state", b"ready", 1, true)
+            .await;
+        sender
+            .publish("hyped/cart_2024/logs", b"not retained", 1, false)
+            .await;
+
+        let (mut late, _) = TestClient::connect(address, "late", None).await;
+        late.subscribe("hyped/cart_2024/+", 1).await;
+        let message = late.receive().await;
+        assert_eq!(message.topic, "hyped/cart_2024/state");
+        assert_eq!(message.payload, b"ready");
+        assert!(message.retain);
+        late.assert_nothing_received().await;
+
+        // An empty retained message clears the topic.
+        sender.publish("hyped/cart_2024/state", b"", 1, true).await;
+        let (mut later, _) = TestClient::connect(address, "later", None).await;
+        later.subscribe("hyped/#", 1).await;
+        later.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn matches_wildcards_at_delivery() {
+        let address = start(true).await;
+        let (mut receiver, _) = TestClient::connect(address, "receiver", None).await;
+        let (mut sender, _) = TestClient::connect(address, "sender", None).await;
+        receiver.subscribe("hyped/+/stm/#", 0).await;
+
+        for topic in [
+            "hyped/cart_2024/base/state/state",
+            "hyped/stm/state",
+            "hyped/cart_2024/stm",
+            "hyped/cart_2024/stm/logs",
+            "hyped/pod_2025/stm/state/state",
+        ] {
+            sender.publish(topic, b"x", 1, false).await;
+        }
+        // `#` also matches the parent level.
+        for topic in [
+            "hyped/cart_2024/stm",
+            "hyped/cart_2024/stm/logs",
+            "hyped/pod_2025/stm/state/state",
+        ] {
+            assert_eq!(receiver.receive().await.topic, topic);
+        }
+        receiver.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn downgrades_qos_to_the_subscription() {
+        let address = start(true).await;
+        let (mut qos0, _) = TestClient::connect(address, "qos0", None).await;
+        let (mut qos1, _) = TestClient::connect(address, "qos1", None).await;
+        let (mut sender, _) = TestClient::connect(address, "sender", None).await;
+        assert_eq!(qos0.subscribe("hyped/#", 0).await, 0);
+        // QoS 2 is not supported, so it is granted as QoS 1.
+        assert_eq!(qos1.subscribe("hyped/#", 2).await, 1);
+
+        sender
+            .publish("hyped/cart_2024/state", b"1", 1, false)
+            .await;
+        let message = qos0.receive().await;
+        assert_eq!((message.qos, message.packet_id), (0, None));
+        let message = qos1.receive().await;
+        assert_eq!(message.qos, 1);
+        assert!(message.packet_id.is_some());
+
+        // Nor is a message upgraded above the QoS it was published with.
+        sender
+            .publish("hyped/cart_2024/state", b"0", 0, false)
+            .await;
+        assert_eq!(qos1.receive().await.qos, 0);
+    }
+
+    #[tokio::test]
+    async fn publishes_wills_unless_disconnected_cleanly() {
+        let address = start(true).await;
+        let (mut watcher, _) = TestClient::connect(address, "watcher", None).await;
+        watcher.subscribe("hyped/+/will", 0).await;
+
+        let will = Some(("hyped/stm/will", b"lost".as_slice()));
+        let (dropped, _) = TestClient::connect(address, "stm", will).await;
+        drop(dropped);
+        let message = watcher.receive().await;
+        assert_eq!(message.topic, "hyped/stm/will");
+        assert_eq!(message.payload, b"lost");
+
+        let will = Some(("hyped/base/will", b"lost".as_slice()));
+        let (clean, _) = TestClient::connect(address, "base", will).await;
+        clean.disconnect().await;
+        watcher.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn forwards_message_expiry_to_mqtt5_subscribers() {
+        let address = start(true).await;
+        let v5 = ProtocolVersion::V5;
+        let (mut sender, _) = TestClient::connect_with(address, v5, "sender", None).await;
+        let (mut live, _) = TestClient::connect_with(address, v5, "live", None).await;
+        let (mut v311, _) = TestClient::connect(address, "v311", None).await;
+        live.subscribe("hyped/+/logs", 1).await;
+        v311.subscribe("hyped/+/logs", 1).await;
+
+        let properties = |expiry| PublishProperties {
+            message_expiry_secs: Some(expiry),
+            // A user property, forwarded as it was sent.
+            forwarded: b"\x26\x00\x01k\x00\x01v".to_vec(),
+        };
+        let expiring = |topic: &str, expiry| Publish {
+            topic: topic.to_string(),
+            payload: b"{}".to_vec(),
+            qos: 1,
+            retain: true,
+            properties: properties(expiry),
+            ..Publish::default()
+        };
+        sender
+            .publish_message(expiring("hyped/stm/logs", 600))
+            .await;
+        sender.publish_message(expiring("hyped/base/logs", 1)).await;
+        assert_eq!(live.receive().await.properties, properties(600));
+        assert_eq!(live.receive().await.properties, properties(1));
+        // MQTT 3.1.1 has no properties to forward them in.
+        let message = v311.receive().await;
+        assert_eq!(message.properties, PublishProperties::default());
+        v311.receive().await;
+
+        // Retained messages are dropped once they expire.
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (mut late, _) = TestClient::connect_with(address, v5, "late", None).await;
+        late.subscribe("hyped/+/logs", 1).await;
+        let message = late.receive().await;
+        assert_eq!(message.topic, "hyped/stm/logs");
+        let expiry = message.properties.message_expiry_secs.unwrap();
+        assert!((598..600).contains(&expiry), "{} seconds left", expiry);
+        late.assert_nothing_received().await;
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_anonymous_access() {
+        let address = start(false).await;
+        let (mut client, reason) = TestClient::connect(address, "board", None).await;
+        // Not authorised.
+        assert_eq!(reason, 0x05);
+        assert!(client.read_packet().await.is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Mqtt,
+    Websockets,
+}
+
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub port: u16,
+    pub bind_address: Option<String>,
+    pub protocol: Protocol,
+}
+
+impl Listener {
+    pub fn address(&self) -> String {
+        let host = self.bind_address.as_deref().unwrap_or("0.0.0.0");
+        format!("{}:{}", host, self.port)
+    }
+}
+
+/// The subset of `mosquitto.conf` this broker understands.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<Listener>,
+    pub allow_anonymous: bool,
+    /// Passwords are never checked, so this only decides whether the broker
+    /// may start, see `main`.
+    pub password_file: Option<String>,
+    /// Options that were recognised as mosquitto options but are not supported here.
+    pub ignored: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: vec![Listener {
+                port: 1883,
+                bind_address: None,
+                protocol: Protocol::Mqtt,
+            }],
+            allow_anonymous: true,
+            password_file: None,
+            ignored: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        Config::parse(&text)
+    }
+
+    /// Parses mosquitto style configuration. `protocol` applies to the most
+    /// recent `listener`, as it does in mosquitto.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config {
+            listeners: Vec::new(),
+            allow_anonymous: false,
+            password_file: None,
+            ignored: Vec::new(),
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let option = words.next().unwrap_or_default();
+            let values: Vec<&str> = words.collect();
+            let error = |message: &str| format!("line {}: {}: {}", index + 1, option, message);
+
+            match (option, values.as_slice()) {
+                ("listener", [port]) | ("listener", [port, _]) => {
+                    let port = port.parse().map_err(|_| error("invalid port"))?;
+                    config.listeners.push(Listener {
+                        port,
+                        bind_address: values.get(1).map(|address| address.to_string()),
+                        protocol: Protocol::Mqtt,
+                    });
+                }
+                ("protocol", [protocol]) => {
+                    let listener = config
+                        .listeners
+                        .last_mut()
+                        .ok_or_else(|| error("must follow a listener"))?;
+                    listener.protocol = match *protocol {
+                        "mqtt" => Protocol::Mqtt,
+                        "websockets" => Protocol::Websockets,
+                        _ => return Err(error("expected mqtt or websockets")),
+                    };
+                }
+                ("allow_anonymous", [value]) => {
+                    config.allow_anonymous = match *value {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(error("expected true or false")),
+                    };
+                }
+                ("password_file", [path]) => config.password_file = Some(path.to_string()),
+                ("listener" | "protocol" | "allow_anonymous" | "password_file", _) => {
+                    return Err(error("wrong number of values"))
+                }
+                _ => config.ignored.push(option.to_string()),
+            }
+        }
+
+        if config.listeners.is_empty() {
+            config.listeners = Config::default().listeners;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_repo_config() {
+        let config = Config::parse(include_str!("../../mqtt/config/mosquitto.conf")).unwrap();
+        assert!(config.allow_anonymous);
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[0].port, 1883);
+        assert_eq!(config.listeners[0].protocol, Protocol::Mqtt);
+        assert_eq!(config.listeners[1].port, 9001);
+        assert_eq!(config.listeners[1].protocol, Protocol::Websockets);
+        assert_eq!(
+            config.password_file.as_deref(),
+            Some("/mosquitto/config/pwfile")
+        );
+        assert_eq!(
+            config.ignored,
+            ["persistence", "persistence_file", "persistence_location"]
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse("# nothing but a comment\n\n").unwrap();
+        assert!(!config.allow_anonymous);
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].address(), "0.0.0.0:1883");
+
+        let config = Config::parse("listener 1884 127.0.0.1").unwrap();
+        assert_eq!(config.listeners[0].address(), "127.0.0.1:1884");
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let errors = [
+            (
+                "protocol websockets",
+                "line 1: protocol: must follow a listener",
+            ),
+            ("listener port", "line 1: listener: invalid port"),
+            (
+                "listener 1883\nprotocol http",
+                "line 2: protocol: expected mqtt or websockets",
+            ),
+            (
+                "allow_anonymous yes",
+                "line 1: allow_anonymous: expected true or false",
+            ),
+            ("listener", "line 1: listener: wrong number of values"),
+            (
+                "password_file",
+                "line 1: password_file: wrong number of values",
+            ),
+        ];
+        for (text, error) in errors {
+            assert_eq!(Config::parse(text).unwrap_err(), error);
+        }
+    }
+}
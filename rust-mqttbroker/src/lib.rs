@@ -0,0 +1,25 @@
+//! A small MQTT broker for the pod network, built as a library as well so
+//! that the host tools can run one in their tests.
+
+pub mod broker;
+pub mod config;
+mod packet;
+mod topic;
+
+use std::sync::Arc;
+
+use broker::Broker;
+use tokio::net::TcpListener;
+
+/// Hands every connection made to `listener` to `broker`.
+pub async fn accept(listener: TcpListener, broker: Arc<Broker>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(broker.clone().handle(stream, peer));
+            }
+            Err(err) => eprintln!("Error accepting connection: {}", err),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+
+use rust_mqttbroker::accept;
+use rust_mqttbroker::broker::Broker;
+use rust_mqttbroker::config::{Config, Listener, Protocol};
+use tokio::net::TcpListener;
+
+const USAGE: &str = "usage: rust-mqttbroker [-c <mosquitto.conf>] [-p <port>] [-v]
+
+  -c, --config   read listener settings from a mosquitto style config file
+  -p, --port     listen on this port instead of the configured listeners
+  -v, --verbose  print every published message";
+
+struct Args {
+    config: Option<PathBuf>,
+    port: Option<u16>,
+    verbose: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        config: None,
+        port: None,
+        verbose: false,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                let path = iter.next().ok_or("missing value for --config")?;
+                args.config = Some(PathBuf::from(path));
+            }
+            "-p" | "--port" => {
+                let port = iter.next().ok_or("missing value for --port")?;
+                args.port = Some(port.parse().map_err(|_| format!("invalid port {}", port))?);
+            }
+            "-v" | "--verbose" => args.verbose = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    Ok(args)
+}
+
+#[tokio::main]
+async fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        exit(2);
+    });
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        }),
+        None => Config::default(),
+    };
+    if let Some(port) = args.port {
+        config.listeners = vec![Listener {
+            port,
+            bind_address: None,
+            protocol: Protocol::Mqtt,
+        }];
+    }
+    for option in &config.ignored {
+        println!("Ignoring unsupported option {}", option);
+    }
+    if config.password_file.is_some() {
+        if !config.allow_anonymous {
+            eprintln!("password_file is not supported, so no client could connect with allow_anonymous false");
+            exit(1);
+        }
+        println!("Ignoring password_file, every client connects anonymously");
+    }
+
+    let broker = Arc::new(Broker::new(config.allow_anonymous, args.verbose));
+    let mut listeners = Vec::new();
+    for listener in &config.listeners {
+        if listener.protocol == Protocol::Websockets {
+            println!("Skipping websockets listener on port {}", listener.port);
+            continue;
+        }
+        let address = listener.address();
+        let socket = TcpListener::bind(&address).await.unwrap_or_else(|err| {
+            eprintln!("Cannot listen on {}: {}", address, err);
+            exit(1);
+        });
+        println!("Listening on {}", address);
+        listeners.push(tokio::spawn(accept(socket, broker.clone())));
+    }
+    if listeners.is_empty() {
+        eprintln!("No MQTT listeners configured");
+        exit(1);
+    }
+    for listener in listeners {
+        let _ = listener.await;
+    }
+}
@@ -0,0 +1,650 @@
+//! MQTT control packet encoding and decoding for protocol levels 4 (3.1.1)
+//! and 5. Only the packets a QoS 0/1 broker needs are supported.
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const PROPERTY_PAYLOAD_FORMAT: u8 = 0x01;
+const PROPERTY_MESSAGE_EXPIRY: u8 = 0x02;
+const PROPERTY_CONTENT_TYPE: u8 = 0x03;
+const PROPERTY_RESPONSE_TOPIC: u8 = 0x08;
+const PROPERTY_CORRELATION_DATA: u8 = 0x09;
+const PROPERTY_SUBSCRIPTION_ID: u8 = 0x0B;
+const PROPERTY_ASSIGNED_CLIENT_ID: u8 = 0x12;
+const PROPERTY_TOPIC_ALIAS: u8 = 0x23;
+const PROPERTY_USER: u8 = 0x26;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub packet_id: Option<u16>,
+    /// Only exchanged with MQTT 5 clients. The properties of wills are not kept.
+    pub properties: PublishProperties,
+}
+
+/// The PUBLISH properties forwarded to subscribers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublishProperties {
+    pub message_expiry_secs: Option<u32>,
+    /// The payload format, content type, response topic, correlation data
+    /// and user properties, still encoded. Topic aliases and subscription
+    /// identifiers only apply to one connection, so they are dropped.
+    pub forwarded: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Connect {
+    pub version: ProtocolVersion,
+    pub client_id: String,
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub will: Option<Publish>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionOptions {
+    pub qos: u8,
+    pub no_local: bool,
+}
+
+#[derive(Debug)]
+pub enum Packet {
+    Connect(Connect),
+    Publish(Publish),
+    Puback,
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<(String, SubscriptionOptions)>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    Pingreq,
+    Disconnect,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Malformed(&'static str),
+    UnsupportedVersion(u8),
+    UnsupportedPacket(u8),
+}
+
+/// Decodes the first packet in `buf`. Returns `Ok(None)` until the whole
+/// packet has been received, otherwise the packet and the number of bytes it
+/// used. `version` is `None` until the CONNECT packet has been seen.
+pub fn decode(
+    buf: &[u8],
+    version: Option<ProtocolVersion>,
+) -> Result<Option<(Packet, usize)>, DecodeError> {
+    let Some(&header) = buf.first() else {
+        return Ok(None);
+    };
+    let mut remaining = 0usize;
+    let mut index = 1;
+    loop {
+        let Some(&byte) = buf.get(index) else {
+            return Ok(None);
+        };
+        if index > 4 {
+            return Err(DecodeError::Malformed("remaining length"));
+        }
+        remaining += ((byte & 0x7F) as usize) << (7 * (index - 1));
+        index += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let end = index + remaining;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let mut reader = Reader {
+        data: &buf[index..end],
+    };
+    let is_v5 = version == Some(ProtocolVersion::V5);
+    let packet = match header >> 4 {
+        CONNECT => Packet::Connect(decode_connect(&mut reader)?),
+        PUBLISH => {
+            let qos = (header >> 1) & 0x03;
+            let topic = reader.string()?;
+            let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+            let properties = if is_v5 {
+                decode_publish_properties(&mut reader)?
+            } else {
+                PublishProperties::default()
+            };
+            Packet::Publish(Publish {
+                topic,
+                payload: reader.data.to_vec(),
+                qos,
+                retain: header & 0x01 != 0,
+                packet_id,
+                properties,
+            })
+        }
+        PUBACK => Packet::Puback,
+        SUBSCRIBE => {
+            let packet_id = reader.u16()?;
+            if is_v5 {
+                reader.skip_properties()?;
+            }
+            let mut filters = Vec::new();
+            while !reader.data.is_empty() {
+                let filter = reader.string()?;
+                let options = reader.u8()?;
+                filters.push((
+                    filter,
+                    SubscriptionOptions {
+                        qos: options & 0x03,
+                        no_local: is_v5 && options & 0x04 != 0,
+                    },
+                ));
+            }
+            if filters.is_empty() {
+                return Err(DecodeError::Malformed("SUBSCRIBE without filters"));
+            }
+            Packet::Subscribe { packet_id, filters }
+        }
+        UNSUBSCRIBE => {
+            let packet_id = reader.u16()?;
+            if is_v5 {
+                reader.skip_properties()?;
+            }
+            let mut filters = Vec::new();
+            while !reader.data.is_empty() {
+                filters.push(reader.string()?);
+            }
+            Packet::Unsubscribe { packet_id, filters }
+        }
+        PINGREQ => Packet::Pingreq,
+        DISCONNECT => Packet::Disconnect,
+        other => return Err(DecodeError::UnsupportedPacket(other)),
+    };
+    Ok(Some((packet, end)))
+}
+
+fn decode_connect(reader: &mut Reader) -> Result<Connect, DecodeError> {
+    let protocol_name = reader.string()?;
+    let level = reader.u8()?;
+    let version = match (protocol_name.as_str(), level) {
+        ("MQTT", 4) => ProtocolVersion::V311,
+        ("MQTT", 5) => ProtocolVersion::V5,
+        _ => return Err(DecodeError::UnsupportedVersion(level)),
+    };
+    let flags = reader.u8()?;
+    let keep_alive = reader.u16()?;
+    if version == ProtocolVersion::V5 {
+        reader.skip_properties()?;
+    }
+    let client_id = reader.string()?;
+
+    let will = if flags & 0x04 != 0 {
+        if version == ProtocolVersion::V5 {
+            reader.skip_properties()?;
+        }
+        let topic = reader.string()?;
+        let payload = reader.binary()?.to_vec();
+        Some(Publish {
+            topic,
+            payload,
+            qos: (flags >> 3) & 0x03,
+            retain: flags & 0x20 != 0,
+            packet_id: None,
+            properties: PublishProperties::default(),
+        })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 {
+        Some(reader.string()?)
+    } else {
+        None
+    };
+
+    Ok(Connect {
+        version,
+        client_id,
+        keep_alive,
+        username,
+        will,
+    })
+}
+
+fn decode_publish_properties(reader: &mut Reader) -> Result<PublishProperties, DecodeError> {
+    let mut properties = reader.properties()?;
+    let mut decoded = PublishProperties::default();
+    while !properties.data.is_empty() {
+        let property = properties.data;
+        match properties.u8()? {
+            PROPERTY_MESSAGE_EXPIRY => {
+                decoded.message_expiry_secs = Some(properties.u32()?);
+                continue;
+            }
+            PROPERTY_TOPIC_ALIAS => {
+                properties.u16()?;
+                continue;
+            }
+            PROPERTY_SUBSCRIPTION_ID => {
+                properties.variable_length()?;
+                continue;
+            }
+            PROPERTY_PAYLOAD_FORMAT => {
+                properties.u8()?;
+            }
+            PROPERTY_CONTENT_TYPE | PROPERTY_RESPONSE_TOPIC => {
+                properties.string()?;
+            }
+            PROPERTY_CORRELATION_DATA => {
+                properties.binary()?;
+            }
+            PROPERTY_USER => {
+                properties.string()?;
+                properties.string()?;
+            }
+            _ => return Err(DecodeError::Malformed("PUBLISH property")),
+        }
+        let length = property.len() - properties.data.len();
+        decoded.forwarded.extend_from_slice(&property[..length]);
+    }
+    Ok(decoded)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < count {
+            return Err(DecodeError::Malformed("packet too short"));
+        }
+        let (value, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.u16()? as usize;
+        self.take(length)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.binary()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Malformed("invalid UTF-8"))
+    }
+
+    fn variable_length(&mut self) -> Result<usize, DecodeError> {
+        let mut length = 0usize;
+        for shift in 0..4 {
+            let byte = self.u8()?;
+            length += ((byte & 0x7F) as usize) << (7 * shift);
+            if byte & 0x80 == 0 {
+                return Ok(length);
+            }
+        }
+        Err(DecodeError::Malformed("variable length"))
+    }
+
+    /// The properties of an MQTT 5 packet, to be read on their own.
+    fn properties(&mut self) -> Result<Reader<'a>, DecodeError> {
+        let length = self.variable_length()?;
+        Ok(Reader {
+            data: self.take(length)?,
+        })
+    }
+
+    fn skip_properties(&mut self) -> Result<(), DecodeError> {
+        self.properties().map(|_| ())
+    }
+}
+
+fn push_variable_length(bytes: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![header];
+    push_variable_length(&mut bytes, body.len());
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+fn push_string(body: &mut Vec<u8>, value: &str) {
+    body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    body.extend_from_slice(value.as_bytes());
+}
+
+/// `assigned_client_id` is only sent to MQTT 5 clients that connected with an
+/// empty client id.
+pub fn connack(version: ProtocolVersion, reason: u8, assigned_client_id: Option<&str>) -> Vec<u8> {
+    let mut body = vec![0, reason];
+    if version == ProtocolVersion::V5 {
+        match assigned_client_id {
+            Some(client_id) => {
+                let mut properties = vec![PROPERTY_ASSIGNED_CLIENT_ID];
+                push_string(&mut properties, client_id);
+                body.push(properties.len() as u8);
+                body.extend_from_slice(&properties);
+            }
+            None => body.push(0),
+        }
+    }
+    packet(CONNACK << 4, &body)
+}
+
+pub fn publish(version: ProtocolVersion, message: &Publish) -> Vec<u8> {
+    let mut body = Vec::with_capacity(message.topic.len() + message.payload.len() + 5);
+    push_string(&mut body, &message.topic);
+    if let Some(packet_id) = message.packet_id {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    if version == ProtocolVersion::V5 {
+        let mut properties = Vec::new();
+        if let Some(expiry) = message.properties.message_expiry_secs {
+            properties.push(PROPERTY_MESSAGE_EXPIRY);
+            properties.extend_from_slice(&expiry.to_be_bytes());
+        }
+        properties.extend_from_slice(&message.properties.forwarded);
+        push_variable_length(&mut body, properties.len());
+        body.extend_from_slice(&properties);
+    }
+    body.extend_from_slice(&message.payload);
+    let header = (PUBLISH << 4) | (message.qos << 1) | message.retain as u8;
+    packet(header, &body)
+}
+
+pub fn puback(version: ProtocolVersion, packet_id: u16) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    if version == ProtocolVersion::V5 {
+        body.extend_from_slice(&[0, 0]);
+    }
+    packet(PUBACK << 4, &body)
+}
+
+pub fn suback(version: ProtocolVersion, packet_id: u16, reasons: &[u8]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    if version == ProtocolVersion::V5 {
+        body.push(0);
+    }
+    body.extend_from_slice(reasons);
+    packet(SUBACK << 4, &body)
+}
+
+pub fn unsuback(version: ProtocolVersion, packet_id: u16, reasons: &[u8]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    if version == ProtocolVersion::V5 {
+        body.push(0);
+        body.extend_from_slice(reasons);
+    }
+    packet(UNSUBACK << 4, &body)
+}
+
+pub fn pingresp() -> Vec<u8> {
+    packet(PINGRESP << 4, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(version: ProtocolVersion, username: Option<&str>) -> Vec<u8> {
+        let mut body = Vec::new();
+        push_string(&mut body, "MQTT");
+        body.push(match version {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        });
+        body.push(0x02 | 0x04 | if username.is_some() { 0x80 } else { 0 });
+        body.extend_from_slice(&30u16.to_be_bytes());
+        if version == ProtocolVersion::V5 {
+            body.push(0);
+        }
+        push_string(&mut body, "board");
+        if version == ProtocolVersion::V5 {
+            body.push(0);
+        }
+        push_string(&mut body, "hyped/will");
+        push_string(&mut body, "gone");
+        if let Some(username) = username {
+            push_string(&mut body, username);
+        }
+        packet(CONNECT << 4, &body)
+    }
+
+    fn message(qos: u8) -> Publish {
+        Publish {
+            topic: "hyped/cart_2024/stm/state/state".to_string(),
+            payload: b"{\"state\":\"Idle\"}".to_vec(),
+            qos,
+            retain: true,
+            packet_id: (qos > 0).then_some(7),
+            properties: PublishProperties::default(),
+        }
+    }
+
+    #[test]
+    fn decodes_connect() {
+        for version in [ProtocolVersion::V311, ProtocolVersion::V5] {
+            let bytes = connect(version, Some("pod"));
+            let Ok(Some((Packet::Connect(connect), used))) = decode(&bytes, None) else {
+                panic!("not a CONNECT");
+            };
+            assert_eq!(used, bytes.len());
+            assert_eq!(connect.version, version);
+            assert_eq!(connect.client_id, "board");
+            assert_eq!(connect.keep_alive, 30);
+            assert_eq!(connect.username.as_deref(), Some("pod"));
+            let will = connect.will.unwrap();
+            assert_eq!(will.topic, "hyped/will");
+            assert_eq!(will.payload, b"gone");
+        }
+    }
+
+    #[test]
+    fn waits_for_partial_packets() {
+        let bytes = connect(ProtocolVersion::V5, None);
+        for length in 0..bytes.len() {
+            assert!(matches!(decode(&bytes[..length], None), Ok(None)));
+        }
+        // A length prefix that is still missing its continuation byte.
+        assert!(matches!(decode(&[0x30, 0x80], None), Ok(None)));
+    }
+
+    #[test]
+    fn decodes_one_packet_at_a_time() {
+        let version = Some(ProtocolVersion::V5);
+        let mut bytes = publish(ProtocolVersion::V5, &message(1));
+        let first = bytes.len();
+        bytes.extend_from_slice(&publish(ProtocolVersion::V5, &message(0)));
+
+        let Ok(Some((Packet::Publish(publish), used))) = decode(&bytes, version) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(used, first);
+        assert_eq!(publish.packet_id, Some(7));
+        assert_eq!(publish.payload, message(1).payload);
+        assert!(publish.retain);
+
+        let Ok(Some((Packet::Publish(publish), used))) = decode(&bytes[first..], version) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(used, bytes.len() - first);
+        assert_eq!((publish.qos, publish.packet_id), (0, None));
+    }
+
+    #[test]
+    fn publish_round_trips() {
+        for version in [ProtocolVersion::V311, ProtocolVersion::V5] {
+            for qos in [0, 1] {
+                let bytes = publish(version, &message(qos));
+                let Ok(Some((Packet::Publish(decoded), _))) = decode(&bytes, Some(version)) else {
+                    panic!("not a PUBLISH");
+                };
+                let expected = message(qos);
+                assert_eq!(decoded.topic, expected.topic);
+                assert_eq!(decoded.payload, expected.payload);
+                assert_eq!(decoded.qos, qos);
+                assert_eq!(decoded.packet_id, expected.packet_id);
+            }
+        }
+    }
+
+    #[test]
+    fn forwards_publish_properties_to_mqtt5() {
+        let mut content_type = vec![PROPERTY_CONTENT_TYPE];
+        push_string(&mut content_type, "application/json");
+        let mut properties = Vec::new();
+        properties.push(PROPERTY_TOPIC_ALIAS);
+        properties.extend_from_slice(&3u16.to_be_bytes());
+        properties.extend_from_slice(&content_type);
+        properties.push(PROPERTY_MESSAGE_EXPIRY);
+        properties.extend_from_slice(&600u32.to_be_bytes());
+        let mut body = Vec::new();
+        push_string(&mut body, "hyped/cart_2024/stm/logs");
+        push_variable_length(&mut body, properties.len());
+        body.extend_from_slice(&properties);
+        body.extend_from_slice(b"{}");
+
+        let version = Some(ProtocolVersion::V5);
+        let Ok(Some((Packet::Publish(decoded), _))) = decode(&packet(PUBLISH << 4, &body), version)
+        else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(decoded.payload, b"{}");
+        let expected = PublishProperties {
+            message_expiry_secs: Some(600),
+            forwarded: content_type,
+        };
+        assert_eq!(decoded.properties, expected);
+
+        let bytes = publish(ProtocolVersion::V5, &decoded);
+        let Ok(Some((Packet::Publish(forwarded), _))) = decode(&bytes, version) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(forwarded.properties, expected);
+        // MQTT 3.1.1 has no properties.
+        let bytes = publish(ProtocolVersion::V311, &decoded);
+        let Ok(Some((Packet::Publish(forwarded), _))) = decode(&bytes, Some(ProtocolVersion::V311))
+        else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(forwarded.payload, b"{}");
+        assert_eq!(forwarded.properties, PublishProperties::default());
+    }
+
+    #[test]
+    fn rejects_oversized_length() {
+        // The remaining length may use at most four bytes.
+        let bytes = [0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(
+            decode(&bytes, None).unwrap_err(),
+            DecodeError::Malformed("remaining length")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let version = Some(ProtocolVersion::V311);
+        let malformed = |bytes: &[u8]| decode(bytes, version).unwrap_err();
+
+        // The topic claims more bytes than the packet holds.
+        let short_topic = packet(PUBLISH << 4, &[0, 10, b'a']);
+        assert_eq!(
+            malformed(&short_topic),
+            DecodeError::Malformed("packet too short")
+        );
+
+        let bad_utf8 = packet(PUBLISH << 4, &[0, 2, 0xC3, 0x28]);
+        assert_eq!(
+            malformed(&bad_utf8),
+            DecodeError::Malformed("invalid UTF-8")
+        );
+
+        let no_filters = packet((SUBSCRIBE << 4) | 0x02, &[0, 1]);
+        assert_eq!(
+            malformed(&no_filters),
+            DecodeError::Malformed("SUBSCRIBE without filters")
+        );
+
+        let mut body = Vec::new();
+        push_string(&mut body, "MQIsdp");
+        body.push(3);
+        assert_eq!(
+            malformed(&packet(CONNECT << 4, &body)),
+            DecodeError::UnsupportedVersion(3)
+        );
+
+        assert_eq!(
+            malformed(&packet(0xF0, &[])),
+            DecodeError::UnsupportedPacket(15)
+        );
+    }
+
+    #[test]
+    fn decodes_subscribe_options() {
+        let mut body = vec![0, 9, 0];
+        push_string(&mut body, "hyped/#");
+        body.push(0x04 | 0x01);
+        push_string(&mut body, "hyped/+/stm/logs");
+        body.push(0);
+        let bytes = packet((SUBSCRIBE << 4) | 0x02, &body);
+
+        let Ok(Some((Packet::Subscribe { packet_id, filters }, _))) =
+            decode(&bytes, Some(ProtocolVersion::V5))
+        else {
+            panic!("not a SUBSCRIBE");
+        };
+        assert_eq!(packet_id, 9);
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0].0, "hyped/#");
+        assert_eq!(filters[0].1.qos, 1);
+        assert!(filters[0].1.no_local);
+        assert_eq!(filters[1].0, "hyped/+/stm/logs");
+        assert!(!filters[1].1.no_local);
+    }
+}
@@ -0,0 +1,92 @@
+/// A topic name used in PUBLISH must be non-empty and free of wildcards.
+pub fn is_valid_name(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#', '\0'])
+}
+
+/// A topic filter may use `+` for a whole level and `#` as the whole last level.
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels
+        .iter()
+        .enumerate()
+        .all(|(index, level)| match *level {
+            "#" => index == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the first level must not match topics such as `$SYS/...`.
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_names() {
+        assert!(is_valid_name("hyped/cart_2024/stm/state/state"));
+        assert!(is_valid_name("/"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("hyped/+/state"));
+        assert!(!is_valid_name("hyped/#"));
+        assert!(!is_valid_name("hyped\0"));
+    }
+
+    #[test]
+    fn validates_filters() {
+        for filter in ["#", "+", "a/#", "a/+/b", "+/+", "/", "a//b", "$SYS/#"] {
+            assert!(is_valid_filter(filter), "{}", filter);
+        }
+        for filter in ["", "a/b#", "a/#/b", "#/a", "a+", "a/+b", "a\0"] {
+            assert!(!is_valid_filter(filter), "{}", filter);
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        let cases = [
+            ("#", "a/b/c", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/b/c", false),
+            ("a/+", "a", false),
+            ("a/+", "a/", true),
+            ("+/+", "/", true),
+            ("+", "/a", false),
+            ("a/b", "a/b", true),
+            ("a/b", "a/b/", false),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(filter, topic), expected, "{} {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn wildcards_skip_system_topics() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+}
@@ -1,18 +1,229 @@
 use async_trait::async_trait;
 use colored::Colorize;
-use hyped_core::{mqtt::ButtonMqttMessage, mqtt_topics::MqttTopics};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hyped_core::{
+    codec::{Codec, ContentType, Postcard},
+    heartbeat::{Heartbeat, LivenessMonitor, BASE_STATION_BOARD},
+    logger::{LogLevel, LogRecord},
+    mqtt::ButtonMqttMessage,
+    mqtt_config::{ConfigError, MqttConfig, QualityOfService},
+    mqtt_messages::{
+        AccelerationMessage, DisplacementMessage, HeartbeatMessage, LogLevelMessage, StateMessage,
+        StateRequestMessage, TopicMessage, VelocityMessage,
+    },
+    mqtt_topics::{MqttTopics, ParsedTopic, TopicNamespace},
+    state_machine::PodState,
+};
 use mqrstt::{
     new_tokio,
-    packets::{self, Packet},
+    packets::{self, Packet, QoS},
     tokio::NetworkStatus,
     AsyncEventHandler, ConnectOptions, MqttClient,
 };
-use serde::{Deserialize, Serialize};
-use serde_json;
 use tokio::time::Duration;
 
+const USAGE: &str = "usage: rust-mqttclient [-c <config file>] [-H <host>] [-p <port>]
+                       [-l <level>] [--board <id>] [--source <text>]
+                       [--set-log-level <target>=<level>] [--to <board>]
+                       [--policies] [--emergency-on-loss]
+
+  -c, --config     read MQTT settings from a file of `key = value` lines
+  -H, --host       broker host, overrides the config file
+  -p, --port       broker port, overrides the config file
+  -l, --log-level  only show logs of at least debug, info, warn or error
+      --board      only show logs from this board
+      --source     only show logs whose source contains this text
+      --set-log-level
+                   change the level of console, mqtt or memory logging on the
+                   boards to debug, info, warn, error or off
+      --to         only change the log level on this board
+      --policies   print how each topic is published and exit
+      --emergency-on-loss
+                   request the Emergency state when a board stops sending
+                   heartbeats
+
+Config file keys: broker_host, broker_port, client_id_prefix, keep_alive_secs,
+max_packet_size, qos, and team, vehicle and board for the topic namespace.";
+
+struct Args {
+    config: Option<PathBuf>,
+    /// Options given on the command line, applied after the config file.
+    overrides: Vec<(&'static str, String)>,
+    log_filter: LogFilter,
+    set_log_level: Option<LogLevelMessage>,
+    /// Board the log level change is addressed to, all boards if unset.
+    to: Option<String>,
+    emergency_on_loss: bool,
+}
+
+/// Which log records from the boards are printed.
+struct LogFilter {
+    min_level: LogLevel,
+    board: Option<String>,
+    source: Option<String>,
+}
+
+impl LogFilter {
+    fn accepts(&self, record: &LogRecord) -> bool {
+        record.level >= self.min_level
+            && self
+                .board
+                .as_ref()
+                .is_none_or(|board| record.board.as_str() == board)
+            && self
+                .source
+                .as_ref()
+                .is_none_or(|source| record.source.contains(source.as_str()))
+    }
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        config: None,
+        overrides: Vec::new(),
+        log_filter: LogFilter {
+            min_level: LogLevel::Debug,
+            board: None,
+            source: None,
+        },
+        set_log_level: None,
+        to: None,
+        emergency_on_loss: false,
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let key = match arg.as_str() {
+            "-c" | "--config" => {
+                let path = iter.next().ok_or("missing value for --config")?;
+                args.config = Some(PathBuf::from(path));
+                continue;
+            }
+            "-l" | "--log-level" => {
+                let level = iter.next().ok_or("missing value for --log-level")?;
+                args.log_filter.min_level = level
+                    .parse()
+                    .map_err(|_| format!("invalid log level {}", level))?;
+                continue;
+            }
+            "--board" => {
+                args.log_filter.board = Some(iter.next().ok_or("missing value for --board")?);
+                continue;
+            }
+            "--source" => {
+                args.log_filter.source = Some(iter.next().ok_or("missing value for --source")?);
+                continue;
+            }
+            "--set-log-level" => {
+                let command = iter.next().ok_or("missing value for --set-log-level")?;
+                args.set_log_level = Some(parse_log_level_command(&command)?);
+                continue;
+            }
+            "--to" => {
+                args.to = Some(iter.next().ok_or("missing value for --to")?);
+                continue;
+            }
+            "--emergency-on-loss" => {
+                args.emergency_on_loss = true;
+                continue;
+            }
+            "--policies" => {
+                print_policies();
+                exit(0);
+            }
+            "-H" | "--host" => "broker_host",
+            "-p" | "--port" => "broker_port",
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        };
+        let value = iter.next().ok_or(format!("missing value for {}", arg))?;
+        args.overrides.push((key, value));
+    }
+    if let (Some(message), Some(board)) = (&mut args.set_log_level, &args.to) {
+        message.board = Some(
+            board
+                .as_str()
+                .try_into()
+                .map_err(|_| format!("board id {} is too long", board))?,
+        );
+    }
+    Ok(args)
+}
+
+/// Parses `<target>=<level>`, e.g. `mqtt=debug` or `console=off`.
+fn parse_log_level_command(command: &str) -> Result<LogLevelMessage, String> {
+    let (target, level) = command
+        .split_once('=')
+        .ok_or_else(|| format!("expected <target>=<level>, got {}", command))?;
+    Ok(LogLevelMessage {
+        board: None,
+        target: target
+            .parse()
+            .map_err(|_| format!("invalid log target {}", target))?,
+        level: match level {
+            "off" => None,
+            level => Some(
+                level
+                    .parse()
+                    .map_err(|_| format!("invalid log level {}", level))?,
+            ),
+        },
+    })
+}
+
+fn set<'a>(config: &mut MqttConfig<'a>, key: &str, value: &'a str) -> Result<(), String> {
+    config.set(key, value).map_err(|err| match err {
+        ConfigError::UnknownKey => format!("unknown option {}", key),
+        ConfigError::InvalidValue => format!("invalid value {} for {}", value, key),
+    })
+}
+
+fn load_config<'a>(
+    text: &'a str,
+    overrides: &'a [(&str, String)],
+) -> Result<MqttConfig<'a>, String> {
+    let mut config = MqttConfig {
+        client_id_prefix: "base-station",
+        namespace: TopicNamespace {
+            board: BASE_STATION_BOARD,
+            ..MqttConfig::DEFAULT.namespace
+        },
+        ..MqttConfig::DEFAULT
+    };
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", index + 1))?;
+        set(&mut config, key.trim(), value.trim())
+            .map_err(|err| format!("line {}: {}", index + 1, err))?;
+    }
+    for (key, value) in overrides {
+        set(&mut config, key, value)?;
+    }
+    Ok(config)
+}
+
+/// Heartbeats missed in a row before a board is reported lost.
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+const HEARTBEAT_INTERVAL_MS: u32 = 1000;
+
+type Liveness = Arc<Mutex<LivenessMonitor<16>>>;
+
 pub struct PingPong {
     pub client: MqttClient,
+    log_filter: LogFilter,
+    liveness: Liveness,
+    started: Instant,
 }
 
 #[async_trait]
@@ -21,15 +232,62 @@ impl AsyncEventHandler for PingPong {
     async fn handle(&mut self, event: packets::Packet) -> () {
         match event {
             Packet::Publish(p) => {
-                if let Ok(payload) = String::from_utf8(p.payload.to_vec()) {
-                    let message: ButtonMqttMessage = serde_json::from_str(&payload).unwrap();
-                    if message.task_id == 1 {
-                        println!("{}", "Ping from main event loop".yellow());
-                    } else if message.task_id == 0 {
-                        println!("Button pressed: {}", message.status);
-                    } else if message.task_id == 2 {
-                        println!("{}", "Ping from five second loop".green());
+                let Some(ParsedTopic {
+                    kind, content_type, ..
+                }) = MqttTopics::from_string(&p.topic)
+                else {
+                    return;
+                };
+                match kind {
+                    MqttTopics::Button => {
+                        if let Some(message) = decode::<ButtonMqttMessage>(content_type, &p.payload)
+                        {
+                            if message.task_id == 0 {
+                                println!("Button pressed: {}", message.status);
+                            }
+                        }
+                    }
+                    MqttTopics::Displacement => {
+                        if let Some(message) =
+                            decode::<DisplacementMessage>(content_type, &p.payload)
+                        {
+                            println!("Displacement: {} m", message.displacement);
+                        }
+                    }
+                    MqttTopics::Velocity => {
+                        if let Some(message) = decode::<VelocityMessage>(content_type, &p.payload) {
+                            println!("Velocity: {} m/s", message.velocity);
+                        }
                     }
+                    MqttTopics::Acceleration => {
+                        if let Some(message) =
+                            decode::<AccelerationMessage>(content_type, &p.payload)
+                        {
+                            println!("Acceleration: {} m/s^2", message.acceleration);
+                        }
+                    }
+                    MqttTopics::State => {
+                        if let Some(message) = decode::<StateMessage>(content_type, &p.payload) {
+                            println!(
+                                "{}",
+                                format!("State: {:?} -> {:?}", message.previous, message.current)
+                                    .cyan()
+                            );
+                        }
+                    }
+                    MqttTopics::Logs => {
+                        if let Some(record) = decode::<LogRecord>(content_type, &p.payload) {
+                            if self.log_filter.accepts(&record) {
+                                print_log(&record);
+                            }
+                        }
+                    }
+                    MqttTopics::Heartbeat => {
+                        if let Some(beat) = decode::<HeartbeatMessage>(content_type, &p.payload) {
+                            self.record_heartbeat(&beat);
+                        }
+                    }
+                    _ => (),
                 }
             }
             Packet::ConnAck(_) => {
@@ -40,26 +298,216 @@ impl AsyncEventHandler for PingPong {
     }
 }
 
+impl PingPong {
+    fn record_heartbeat(&self, beat: &HeartbeatMessage) {
+        if beat.board.as_str() == BASE_STATION_BOARD {
+            return;
+        }
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        if self.liveness.lock().unwrap().record(beat, now_ms) {
+            println!("{}", format!("Board {} is back", beat.board).green());
+        }
+        let unhealthy = beat.tasks & !beat.healthy;
+        if unhealthy != 0 {
+            println!(
+                "{}",
+                format!(
+                    "Board {} tasks {:#b} did not check in",
+                    beat.board, unhealthy
+                )
+                .yellow()
+            );
+        }
+    }
+}
+
+/// Publishes `message` as JSON according to its topic's policy.
+async fn publish<M: TopicMessage>(
+    client: &MqttClient,
+    namespace: &TopicNamespace<'_>,
+    message: &M,
+) {
+    let topic = namespace.topic(M::TOPIC, ContentType::Json).unwrap();
+    let policy = M::TOPIC.policy();
+    let properties = packets::PublishProperties {
+        message_expiry_interval: policy.expiry_secs,
+        ..Default::default()
+    };
+    client
+        .publish_with_properties(
+            topic.to_string(),
+            qos_level(policy.qos),
+            policy.retain,
+            serde_json::to_vec(message).unwrap(),
+            properties,
+        )
+        .await
+        .unwrap();
+}
+
+/// Publishes the base station's heartbeat and reports boards that stopped
+/// sending theirs.
+async fn send_heartbeats(
+    client: &MqttClient,
+    namespace: &TopicNamespace<'_>,
+    liveness: &Liveness,
+    started: Instant,
+    emergency_on_loss: bool,
+) {
+    const TASK_MONITOR: u32 = 1;
+    // The boards only accept the base station's heartbeats under this id,
+    // whatever board the namespace names.
+    let heartbeat = Heartbeat::new(BASE_STATION_BOARD, TASK_MONITOR);
+    let mut interval = tokio::time::interval(Duration::from_millis(HEARTBEAT_INTERVAL_MS.into()));
+    loop {
+        interval.tick().await;
+        let now_ms = started.elapsed().as_millis() as u64;
+        heartbeat.check_in(TASK_MONITOR);
+        publish(client, namespace, &heartbeat.beat(now_ms)).await;
+        let mut lost = Vec::new();
+        liveness
+            .lock()
+            .unwrap()
+            .check(now_ms, |board| lost.push(board.board.to_string()));
+        for board in lost {
+            println!("{}", format!("Lost board {}", board).red());
+            if emergency_on_loss {
+                let request = StateRequestMessage {
+                    requested: PodState::Emergency,
+                };
+                publish(client, namespace, &request).await;
+                println!("{}", "Requested the Emergency state".red());
+            }
+        }
+    }
+}
+
+fn print_policies() {
+    println!(
+        "{:<26} {:<5} {:<7} {:>13} {:>8}",
+        "topic", "qos", "retain", "min interval", "expiry"
+    );
+    let optional = |value: Option<u32>, unit: &str| {
+        value.map_or("-".to_string(), |value| format!("{} {}", value, unit))
+    };
+    for topic in MqttTopics::ALL {
+        let policy = topic.policy();
+        println!(
+            "{:<26} {:<5} {:<7} {:>13} {:>8}",
+            topic.path(),
+            format!("{:?}", policy.qos),
+            policy.retain,
+            optional(policy.min_interval_ms, "ms"),
+            optional(policy.expiry_secs, "s")
+        );
+    }
+}
+
+fn qos_level(qos: QualityOfService) -> QoS {
+    match qos {
+        QualityOfService::QoS0 => QoS::AtMostOnce,
+        QualityOfService::QoS2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+fn print_log(record: &LogRecord) {
+    let line = format!(
+        "{:>10.3}s {} {} #{} [{:?}] {}",
+        record.timestamp_ms as f64 / 1000.0,
+        record.board,
+        record.source,
+        record.sequence,
+        record.level,
+        record.message
+    );
+    match record.level {
+        LogLevel::Debug => println!("{}", line.dimmed()),
+        LogLevel::Info => println!("{}", line),
+        LogLevel::Warn => println!("{}", line.yellow()),
+        LogLevel::Error => println!("{}", line.red()),
+    }
+}
+
+fn decode<M: TopicMessage>(content_type: ContentType, payload: &[u8]) -> Option<M> {
+    let message = match content_type {
+        ContentType::Json => serde_json::from_slice(payload).map_err(|err| err.to_string()),
+        ContentType::Postcard => Postcard::decode(payload).map_err(|err| format!("{:?}", err)),
+    };
+    match message {
+        Ok(message) => Some(message),
+        Err(err) => {
+            println!(
+                "{}",
+                format!("Could not decode {:?}: {}", M::TOPIC, err).red()
+            );
+            None
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let options = ConnectOptions::new("rust-client".to_string());
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}\n{}", err, USAGE);
+        exit(2);
+    });
+    let text = match &args.config {
+        Some(path) => std::fs::read_to_string(path).unwrap_or_else(|err| {
+            eprintln!("cannot read {}: {}", path.display(), err);
+            exit(1);
+        }),
+        None => String::new(),
+    };
+    let config = load_config(&text, &args.overrides).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(1);
+    });
+    let qos = qos_level(config.qos);
+
+    let client_id = config.client_id::<64>("monitor").unwrap();
+    let mut options = ConnectOptions::new(client_id.to_string());
+    options.keep_alive_interval_s = config.keep_alive_secs.into();
 
     let (mut network, client) = new_tokio(options);
 
-    let stream = tokio::net::TcpStream::connect(("localhost", 1883))
+    let stream = tokio::net::TcpStream::connect((config.broker_host, config.broker_port))
         .await
         .unwrap();
 
+    let started = Instant::now();
+    let liveness: Liveness = Arc::new(Mutex::new(LivenessMonitor::new(
+        HEARTBEAT_INTERVAL_MS,
+        MAX_MISSED_HEARTBEATS,
+    )));
     let mut pingpong = PingPong {
         client: client.clone(),
+        log_filter: args.log_filter,
+        liveness: liveness.clone(),
+        started,
     };
 
     network.connect(stream, &mut pingpong).await.unwrap();
 
-    client
-        .subscribe(MqttTopics::to_string(&MqttTopics::Acceleration))
-        .await
-        .unwrap();
+    for topic in [
+        MqttTopics::Button,
+        MqttTopics::State,
+        MqttTopics::Displacement,
+        MqttTopics::Velocity,
+        MqttTopics::Acceleration,
+        MqttTopics::Logs,
+        MqttTopics::Heartbeat,
+    ] {
+        for content_type in ContentType::ALL {
+            let filter = config.namespace.any_board(topic, content_type).unwrap();
+            client.subscribe((filter.as_str(), qos)).await.unwrap();
+        }
+    }
+
+    if let Some(message) = &args.set_log_level {
+        publish(&client, &config.namespace, message).await;
+        println!("Sent log level change: {:?}", message);
+    }
 
     let (n, _) = tokio::join!(
         async {
@@ -71,9 +519,278 @@ async fn main() {
             }
         },
         async {
-            tokio::time::sleep(Duration::from_secs(6000)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(6000)) => {}
+                _ = send_heartbeats(&client, &config.namespace, &liveness, started, args.emergency_on_loss) => {}
+            }
             client.disconnect().await.unwrap();
         }
     );
     assert!(n.is_ok());
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use hyped_core::{
+        codec::ContentType,
+        heartbeat::BASE_STATION_BOARD,
+        logger::{LogLevel, LogRecord, LogTarget},
+        mqtt::HypedMqttClient,
+        mqtt_config::MqttConfig,
+        mqtt_connection::Transport,
+        mqtt_messages::{StateMessage, StateRequestMessage},
+        mqtt_tcp::TcpTransport,
+        mqtt_topics::MqttTopics,
+        state_machine::PodState,
+    };
+    use mqrstt::{
+        new_tokio,
+        packets::{self, Packet},
+        AsyncEventHandler, ConnectOptions, MqttClient,
+    };
+    use rust_mqtt::{client::client::MqttClient as BoardClient, utils::rng_generator::CountingRng};
+    use rust_mqttbroker::broker::Broker;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use tokio::time::{timeout, Duration};
+
+    use super::{decode, load_config, parse_log_level_command, publish, LogFilter};
+
+    /// How long to wait for a packet that should arrive.
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Runs a broker on a free local port, as `rust-mqttbroker` does.
+    async fn start_broker() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let broker = Arc::new(Broker::new(true, false));
+        tokio::spawn(rust_mqttbroker::accept(listener, broker));
+        address
+    }
+
+    /// Passes every packet the host tool's client receives to the test.
+    struct Recorder(UnboundedSender<Packet>);
+
+    #[async_trait]
+    impl AsyncEventHandler for Recorder {
+        async fn handle(&mut self, event: Packet) {
+            let _ = self.0.send(event);
+        }
+    }
+
+    /// Connects the host tool's client, as `main` does.
+    async fn connect_host(address: SocketAddr) -> (MqttClient, UnboundedReceiver<Packet>) {
+        let (mut network, client) = new_tokio(ConnectOptions::new("base-station-test".to_string()));
+        let (sender, mut packets) = unbounded_channel();
+        let mut recorder = Recorder(sender);
+        let stream = TcpStream::connect(address).await.unwrap();
+        network.connect(stream, &mut recorder).await.unwrap();
+        tokio::spawn(async move { while network.poll(&mut recorder).await.is_ok() {} });
+        assert!(matches!(next(&mut packets).await, Packet::ConnAck(_)));
+        (client, packets)
+    }
+
+    async fn next(packets: &mut UnboundedReceiver<Packet>) -> Packet {
+        timeout(RECEIVE_TIMEOUT, packets.recv())
+            .await
+            .expect("no packet from the broker")
+            .unwrap()
+    }
+
+    async fn next_publish(packets: &mut UnboundedReceiver<Packet>) -> packets::Publish {
+        loop {
+            if let Packet::Publish(message) = next(packets).await {
+                return message;
+            }
+        }
+    }
+
+    /// Runs `board` on its own thread with a client that has connected to the
+    /// broker over TCP, as the firmware does on the board.
+    fn run_board<T: Send + 'static>(
+        address: SocketAddr,
+        board: impl FnOnce(&mut HypedMqttClient<'_, TcpTransport, CountingRng>) -> T + Send + 'static,
+    ) -> tokio::task::JoinHandle<T> {
+        tokio::task::spawn_blocking(move || {
+            let config = MqttConfig::DEFAULT;
+            let mut transport = TcpTransport::new(address);
+            embassy_futures::block_on(transport.reconnect()).unwrap();
+            let (mut write_buffer, mut recv_buffer) = ([0; 1024], [0; 1024]);
+            let mut client = HypedMqttClient {
+                client: BoardClient::new(
+                    transport,
+                    &mut write_buffer,
+                    1024,
+                    &mut recv_buffer,
+                    1024,
+                    config.client_config("stm-test", CountingRng(0)),
+                ),
+                namespace: config.namespace,
+            };
+            embassy_futures::block_on(client.connect_to_broker()).unwrap();
+            board(&mut client)
+        })
+    }
+
+    fn record(level: LogLevel, board: &str, source: &str) -> LogRecord {
+        LogRecord {
+            level,
+            board: board.try_into().unwrap(),
+            source: source.try_into().unwrap(),
+            timestamp_ms: 0,
+            sequence: 0,
+            message: "check".try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn log_filter_checks_level_board_and_source() {
+        let everything = LogFilter {
+            min_level: LogLevel::Debug,
+            board: None,
+            source: None,
+        };
+        assert!(everything.accepts(&record(LogLevel::Debug, "stm", "")));
+
+        let filter = LogFilter {
+            min_level: LogLevel::Warn,
+            board: Some("stm".to_string()),
+            source: Some("sensors".to_string()),
+        };
+        assert!(filter.accepts(&record(LogLevel::Warn, "stm", "stm::sensors::imu")));
+        assert!(filter.accepts(&record(LogLevel::Error, "stm", "sensors")));
+        assert!(!filter.accepts(&record(LogLevel::Info, "stm", "sensors")));
+        // Boards are matched exactly, sources by any part.
+        assert!(!filter.accepts(&record(LogLevel::Warn, "stm2", "sensors")));
+        assert!(!filter.accepts(&record(LogLevel::Warn, "st", "sensors")));
+        assert!(!filter.accepts(&record(LogLevel::Warn, "stm", "mqtt")));
+    }
+
+    #[test]
+    fn log_level_commands_name_a_target_and_level() {
+        let command = parse_log_level_command("mqtt=debug").unwrap();
+        assert_eq!(command.target, LogTarget::Mqtt);
+        assert_eq!(command.level, Some(LogLevel::Debug));
+        assert_eq!(command.board, None);
+        let command = parse_log_level_command("console=off").unwrap();
+        assert_eq!(command.target, LogTarget::Console);
+        assert_eq!(command.level, None);
+
+        let errors = [
+            ("mqtt", "expected <target>=<level>, got mqtt"),
+            ("mqtt:debug", "expected <target>=<level>, got mqtt:debug"),
+            ("mqtt=loud", "invalid log level loud"),
+            ("mqtt=", "invalid log level "),
+            ("disk=info", "invalid log target disk"),
+        ];
+        for (command, error) in errors {
+            let parsed = parse_log_level_command(command);
+            assert_eq!(parsed.err().as_deref(), Some(error));
+        }
+    }
+
+    #[test]
+    fn config_files_skip_comments_and_blank_lines() {
+        let text = "# Test stand\n\n  broker_host = 10.0.0.2  \nbroker_port=1884\n# board = stm\n";
+        let overrides = [("broker_port", "1885".to_string())];
+        let config = load_config(text, &overrides).unwrap();
+        assert_eq!(config.broker_host, "10.0.0.2");
+        assert_eq!(config.broker_port, 1885);
+        assert_eq!(config.client_id_prefix, "base-station");
+        assert_eq!(config.namespace.board, BASE_STATION_BOARD);
+    }
+
+    #[test]
+    fn config_errors_name_the_line() {
+        let errors = [
+            ("qos = 1\ncolour = red", "line 2: unknown option colour"),
+            (
+                "broker_port = many",
+                "line 1: invalid value many for broker_port",
+            ),
+            ("\nboard = a/b", "line 2: invalid value a/b for board"),
+            ("broker_host", "line 1: expected key = value"),
+        ];
+        for (text, error) in errors {
+            assert_eq!(load_config(text, &[]).err().as_deref(), Some(error));
+        }
+        let overrides = [("qos", "3".to_string())];
+        let error = load_config("", &overrides).err();
+        assert_eq!(error.as_deref(), Some("invalid value 3 for qos"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn boards_receive_requests_from_the_host_tool() {
+        let address = start_broker().await;
+        let (subscribed, is_subscribed) = std::sync::mpsc::channel();
+        let board = run_board(address, move |client| {
+            let filter = client
+                .namespace
+                .any_board(MqttTopics::StateRequest, ContentType::Json)
+                .unwrap();
+            embassy_futures::block_on(client.subscribe(filter.as_str())).unwrap();
+            subscribed.send(()).unwrap();
+            embassy_futures::block_on(client.receive::<StateRequestMessage>()).unwrap()
+        });
+        is_subscribed.recv_timeout(RECEIVE_TIMEOUT).unwrap();
+
+        let config = load_config("", &[]).unwrap();
+        let (host, mut packets) = connect_host(address).await;
+        let filter = config
+            .namespace
+            .topic(MqttTopics::StateRequest, ContentType::Json)
+            .unwrap();
+        // Packets from one client reach the broker in order, so it is
+        // subscribed by the time the request arrives.
+        host.subscribe(filter.as_str()).await.unwrap();
+        let request = StateRequestMessage {
+            requested: PodState::Emergency,
+        };
+        publish(&host, &config.namespace, &request).await;
+
+        let received = timeout(RECEIVE_TIMEOUT, board).await.unwrap().unwrap();
+        assert_eq!(received.requested, PodState::Emergency);
+        // The broker forwards the expiry the host tool sets from the policy.
+        let echoed = next_publish(&mut packets).await;
+        assert_eq!(
+            echoed.publish_properties.message_expiry_interval,
+            MqttTopics::StateRequest.policy().expiry_secs
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_host_tool_decodes_board_messages() {
+        let address = start_broker().await;
+        let state = StateMessage {
+            previous: PodState::Idle,
+            current: PodState::Calibrating,
+            timestamp_ms: 1500,
+        };
+        run_board(address, move |client| {
+            embassy_futures::block_on(client.publish(&state)).unwrap();
+        })
+        .await
+        .unwrap();
+
+        // The state is retained, so the host tool gets it when it subscribes.
+        let (host, mut packets) = connect_host(address).await;
+        let config = MqttConfig::DEFAULT;
+        let filter = config
+            .namespace
+            .any_board(MqttTopics::State, ContentType::Json)
+            .unwrap();
+        host.subscribe(filter.as_str()).await.unwrap();
+        let message = next_publish(&mut packets).await;
+        let topic = config
+            .namespace
+            .topic(MqttTopics::State, ContentType::Json)
+            .unwrap();
+        assert_eq!(message.topic, topic.as_str());
+        let decoded = decode::<StateMessage>(ContentType::Json, &message.payload).unwrap();
+        assert_eq!(decoded, state);
+    }
+}
@@ -1,11 +1,10 @@
 #![no_std]
 #![no_main]
 
-use core::str::FromStr;
-
 use defmt::*;
 use {defmt_rtt as _, panic_probe as _};
 
+use core::cell::RefCell;
 use embassy_executor::Spawner;
 use embassy_net::{tcp::TcpSocket, Ipv4Address, Ipv4Cidr, Stack, StackResources};
 use embassy_stm32::{bind_interrupts, eth, gpio::Input, time::Hertz};
@@ -15,44 +14,106 @@ use embassy_stm32::{
 };
 use embassy_stm32::{gpio::AnyPin, peripherals::ETH};
 use embassy_stm32::{gpio::Pin, Config};
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::Channel;
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
 use static_cell::StaticCell;
 
 // MQTT related imports
 use heapless::String;
-use rust_mqtt::{
-    client::{client::MqttClient, client_config::ClientConfig},
-    utils::rng_generator::CountingRng,
-};
-use typenum::consts::*;
+use rust_mqtt::utils::rng_generator::CountingRng;
 
 use hyped_core::{
-    format_string,
-    logger::LogLevel,
-    mqtt::{initialise_mqtt_config, ButtonMqttMessage, HypedMqttClient, MqttMessage},
-    mqtt_topics::MqttTopics,
+    codec::ContentType,
+    heartbeat::{Heartbeat, LivenessMonitor, BASE_STATION_BOARD},
+    hlog_error, hlog_info, hlog_warn,
+    log_sinks::MqttSink,
+    logger::{DefmtSink, LogLevel, LogTarget, Logger, RateLimit},
+    mqtt::{ButtonMqttMessage, Error, MqttMessage},
+    mqtt_config::MqttConfig,
+    mqtt_connection::{Backoff, Delay, MqttSupervisor, Transport},
+    mqtt_messages::{HeartbeatMessage, StateRequestMessage},
+    mqtt_session::{MessageHandler, MultiplexedSession},
+    mqtt_topics::{MqttTopics, ParsedTopic, TopicFilter, TopicNamespace},
+    priority_channel::PriorityChannel,
+    state_machine::{PodState, StateMachine},
 };
 
 bind_interrupts!(struct Irqs {
     ETH => eth::InterruptHandler;
 });
 
-static SEND_CHANNEL: Channel<ThreadModeRawMutex, MqttMessage, 128> = Channel::new();
+static SEND_CHANNEL: PriorityChannel<ThreadModeRawMutex, MqttMessage, 8, 128> =
+    PriorityChannel::new();
 
-async fn log(level: LogLevel, message: &str) {
-    match level {
-        LogLevel::Info => info!("{}", message),
-        LogLevel::Warn => warn!("{}", message),
-        LogLevel::Error => error!("{}", message),
-        LogLevel::Debug => debug!("{}", message),
-    }
+/// The track network broker, unless overridden by `HYPED_MQTT_*` variables
+/// when building. They are applied at compile time, so an invalid value fails
+/// the build and the logger and heartbeat name the board their topics are
+/// published from.
+const MQTT_CONFIG: MqttConfig<'static> = MqttConfig {
+    broker_host: "169.254.195.141",
+    client_id_prefix: "stm",
+    namespace: TopicNamespace {
+        board: "stm",
+        ..MqttConfig::DEFAULT.namespace
+    },
+    ..MqttConfig::DEFAULT
+}
+.with_build_env();
+
+/// The boards have no DNS, so the broker host has to be an IPv4 address.
+const BROKER_IPV4: [u8; 4] = match MQTT_CONFIG.broker_ipv4() {
+    Some(address) => address,
+    None => core::panic!("the broker host of a board must be an IPv4 address"),
+};
+
+/// Keeps the last 16 log records while the broker cannot be reached.
+static MQTT_LOG_SINK: MqttSink<ThreadModeRawMutex, 8, 128, 16> = MqttSink::new(&SEND_CHANNEL);
+
+static LOGGER: Logger<'static> = Logger::new(MQTT_CONFIG.namespace.board, uptime_ms)
+    .with_sink(LogTarget::Console, &DefmtSink, LogLevel::Debug)
+    .with_sink(LogTarget::Mqtt, &MQTT_LOG_SINK, LogLevel::Info)
+    .with_deduplication(LogLevel::Info)
+    .with_deduplication(LogLevel::Warn)
+    .with_deduplication(LogLevel::Error)
+    .with_rate_limit(LogLevel::Debug, LOG_RATE_LIMIT)
+    .with_rate_limit(LogLevel::Info, LOG_RATE_LIMIT);
+
+const LOG_RATE_LIMIT: RateLimit = RateLimit {
+    max_records: 20,
+    window_ms: 1000,
+};
+
+static STATE_MACHINE: Mutex<ThreadModeRawMutex, RefCell<StateMachine>> =
+    Mutex::new(RefCell::new(StateMachine::new()));
+
+// One bit per task in the heartbeat.
+const TASK_MAIN: u32 = 1 << 0;
+const TASK_BUTTON: u32 = 1 << 1;
+
+const HEARTBEAT_INTERVAL_MS: u32 = 1000;
+/// Base station heartbeats missed in a row before the pod is stopped.
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+static HEARTBEAT: Heartbeat<'static> =
+    Heartbeat::new(MQTT_CONFIG.namespace.board, TASK_MAIN | TASK_BUTTON);
+
+static BASE_STATION: Mutex<ThreadModeRawMutex, RefCell<LivenessMonitor<1>>> =
+    Mutex::new(RefCell::new(LivenessMonitor::new(
+        HEARTBEAT_INTERVAL_MS,
+        MAX_MISSED_HEARTBEATS,
+    )));
+
+fn uptime_ms() -> u64 {
+    Instant::now().as_millis()
+}
+
+/// Queues a message for the send task, ahead of telemetry if its topic is high
+/// priority. The message is stamped now, so a wait in the queue does not count
+/// against its topic's rate limit.
+async fn send(message: MqttMessage) {
+    let priority = message.priority();
     SEND_CHANNEL
-        .send(MqttMessage {
-            topic: MqttTopics::to_string(&MqttTopics::Logs),
-            payload: String::<512>::from_str(message).unwrap(),
-        })
+        .send(message.created_at(uptime_ms()), priority)
         .await;
 }
 
@@ -65,177 +126,273 @@ async fn net_task(stack: &'static Stack<Ethernet<'static, ETH, GenericSMI>>) ->
 async fn button_task(pin: AnyPin) {
     let button: Input<_> = Input::new(pin, Pull::Down);
     loop {
-        SEND_CHANNEL
-            .send(MqttMessage {
-                topic: MqttTopics::to_string(&MqttTopics::Acceleration),
-                payload: String::<512>::from_str(
-                    serde_json_core::to_string::<U512, ButtonMqttMessage>(&ButtonMqttMessage {
-                        task_id: 0,
-                        status: button.is_high(),
-                    })
-                    .unwrap()
-                    .as_str(),
-                )
-                .unwrap(),
-            })
-            .await;
+        HEARTBEAT.check_in(TASK_BUTTON);
+        send(unwrap!(MqttMessage::from_message(&ButtonMqttMessage {
+            task_id: 0,
+            status: button.is_high(),
+        })))
+        .await;
         Timer::after(Duration::from_millis(100)).await;
     }
 }
 
+/// Publishes this board's heartbeat and stops the pod if the base station's
+/// heartbeats stop arriving. The check runs on every beat while the base
+/// station is lost, so a pod that leaves Idle afterwards is stopped as well.
 #[embassy_executor::task]
-async fn five_seconds_task() {
+async fn heartbeat_task() {
+    BASE_STATION
+        .lock(|monitor| unwrap!(monitor.borrow_mut().watch(BASE_STATION_BOARD, uptime_ms())));
     loop {
-        log(LogLevel::Info, "Ping from five second loop").await;
-        SEND_CHANNEL
-            .send(MqttMessage {
-                topic: MqttTopics::to_string(&MqttTopics::Acceleration),
-                payload: String::<512>::from_str(
-                    serde_json_core::to_string::<U512, ButtonMqttMessage>(&ButtonMqttMessage {
-                        task_id: 2,
-                        status: false,
-                    })
-                    .unwrap()
-                    .as_str(),
-                )
-                .unwrap(),
-            })
-            .await;
-        Timer::after(Duration::from_secs(5)).await;
+        let now_ms = uptime_ms();
+        let beat = unwrap!(MqttMessage::from_message(&HEARTBEAT.beat(now_ms)));
+        // Never waits for room in the queue, which would hold up the check.
+        let priority = beat.priority();
+        if SEND_CHANNEL
+            .try_send(beat.created_at(now_ms), priority)
+            .is_err()
+        {
+            warn!("Send queue full, dropping heartbeat");
+        }
+        let (newly_lost, lost) = BASE_STATION.lock(|monitor| {
+            let mut monitor = monitor.borrow_mut();
+            let mut newly_lost = false;
+            monitor.check(now_ms, |_| newly_lost = true);
+            (
+                newly_lost,
+                monitor.is_lost(BASE_STATION_BOARD) == Some(true),
+            )
+        });
+        if lost {
+            emergency_stop(newly_lost).await;
+        }
+        // Reports repeated log records that stopped repeating.
+        LOGGER.flush();
+        Timer::after(Duration::from_millis(HEARTBEAT_INTERVAL_MS.into())).await;
     }
 }
 
-#[embassy_executor::task]
-async fn mqtt_send_task(stack: &'static Stack<Ethernet<'static, ETH, GenericSMI>>) {
-    let mut rx_buffer: [u8; 4096] = [0; 4096];
-    let mut tx_buffer: [u8; 4096] = [0; 4096];
-    let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
-    socket.set_timeout(Some(embassy_time::Duration::from_secs(60)));
-    log(LogLevel::Info, "Connecting to Send Socket...").await;
-    match socket
-        .connect((Ipv4Address::new(169, 254, 195, 141), 1883))
-        .await
-    {
-        Ok(()) => log(LogLevel::Info, "Connected to Send!").await,
-        Err(connection_error) => {
-            log(
-                LogLevel::Error,
-                format_string::show(
-                    &mut [0; 1024],
-                    format_args!("Error connecting: {:?}", connection_error),
-                )
-                .unwrap(),
-            )
-            .await;
+/// Enters Emergency unless the pod is Idle or already there. `newly_lost`
+/// is only set on the first check after the base station was lost.
+async fn emergency_stop(newly_lost: bool) {
+    let transition = STATE_MACHINE.lock(|state_machine| {
+        let mut state_machine = state_machine.borrow_mut();
+        // Nothing to stop before the pod has left Idle.
+        match state_machine.current() {
+            PodState::Idle | PodState::Emergency => None,
+            _ => Some(state_machine.transition(PodState::Emergency, uptime_ms())),
         }
-    };
+    });
+    match transition {
+        Some(Ok(transition)) => {
+            hlog_error!(LOGGER, "Lost the base station, entering Emergency");
+            send(unwrap!(MqttMessage::from_message(&transition))).await
+        }
+        Some(Err(err)) => warn!("Cannot enter Emergency: {:?}", err),
+        None if newly_lost => hlog_warn!(LOGGER, "Lost the base station"),
+        None => {}
+    }
+}
 
-    let config = initialise_mqtt_config();
-    let mut recv_buffer = [0; 1024];
-    let mut write_buffer = [0; 1024];
-    let client = MqttClient::<_, 5, _>::new(
-        socket,
-        &mut write_buffer,
-        1024,
-        &mut recv_buffer,
-        1024,
-        config,
-    );
-    let mut mqtt_client = HypedMqttClient { client };
+struct TcpTransport<'a> {
+    socket: TcpSocket<'a>,
+    endpoint: (Ipv4Address, u16),
+}
+
+impl embedded_io_async::ErrorType for TcpTransport<'_> {
+    type Error = embassy_net::tcp::Error;
+}
 
-    mqtt_client.connect_to_broker().await;
+impl embedded_io_async::Read for TcpTransport<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.socket.read(buf).await
+    }
+}
 
-    loop {
-        while !SEND_CHANNEL.is_empty() {
-            let message = SEND_CHANNEL.receive().await;
+impl embedded_io_async::ReadReady for TcpTransport<'_> {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        embedded_io_async::ReadReady::read_ready(&mut self.socket)
+    }
+}
 
-            mqtt_client
-                .send_message(message.topic.as_str(), message.payload.as_bytes(), true)
-                .await;
+impl embedded_io_async::Write for TcpTransport<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.flush().await
+    }
+}
+
+impl Transport for TcpTransport<'_> {
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        self.socket.abort();
+        let _ = self.socket.flush().await;
+        match self.socket.connect(self.endpoint).await {
+            Ok(()) => Ok(()),
+            Err(connection_error) => {
+                error!("Error connecting: {:?}", connection_error);
+                Err(Error::Network)
+            }
         }
-        Timer::after(Duration::from_millis(100)).await;
+    }
+}
+
+struct EmbassyDelay;
+
+impl Delay for EmbassyDelay {
+    async fn delay_ms(&mut self, millis: u64) {
+        Timer::after(Duration::from_millis(millis)).await;
+    }
+}
+
+struct BoardHandler {
+    /// Log records lost while offline that have already been reported.
+    overwritten_logs: u32,
+    /// Log records too large to publish that have already been reported.
+    dropped_logs: u32,
+}
+
+impl MessageHandler for BoardHandler {
+    async fn handle(&mut self, topic: &str, payload: &[u8]) -> Option<MqttMessage> {
+        match MqttTopics::from_string(topic) {
+            Some(ParsedTopic {
+                kind: MqttTopics::StateRequest,
+                content_type,
+                ..
+            }) => {
+                let timestamp_ms = uptime_ms();
+                match content_type.decode::<StateRequestMessage>(payload) {
+                    Ok(request) => {
+                        match STATE_MACHINE.lock(|state_machine| {
+                            state_machine
+                                .borrow_mut()
+                                .handle_request(&request, timestamp_ms)
+                        }) {
+                            // Published by the session, as this task is the
+                            // one emptying SEND_CHANNEL.
+                            Ok(transition) => {
+                                return Some(unwrap!(MqttMessage::from_message(&transition)))
+                            }
+                            Err(err) => warn!("Rejected state request: {:?}", err),
+                        }
+                    }
+                    Err(err) => warn!("Invalid state request: {:?}", err),
+                }
+            }
+            Some(ParsedTopic {
+                kind: MqttTopics::LogLevel,
+                content_type,
+                ..
+            }) => {
+                if let Err(err) = LOGGER.apply_payload(content_type, payload) {
+                    warn!("Invalid log level message: {:?}", err);
+                }
+            }
+            Some(ParsedTopic {
+                kind: MqttTopics::Heartbeat,
+                content_type,
+                ..
+            }) => match content_type.decode::<HeartbeatMessage>(payload) {
+                Ok(beat) if beat.board.as_str() == BASE_STATION_BOARD => {
+                    let recovered = BASE_STATION
+                        .lock(|monitor| monitor.borrow_mut().record(&beat, uptime_ms()));
+                    if recovered {
+                        hlog_info!(LOGGER, "Base station is back");
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Invalid heartbeat: {:?}", err),
+            },
+            // Not forwarded to the broker, as each log record is published
+            // on the same session as the state requests.
+            _ => debug!("Ignored {} bytes on topic {}", payload.len(), topic),
+        }
+        None
+    }
+
+    fn connected(&mut self) {
+        MQTT_LOG_SINK.set_online(true);
+        let overwritten = MQTT_LOG_SINK.overwritten();
+        if overwritten > self.overwritten_logs {
+            hlog_warn!(
+                LOGGER,
+                "{} log records were lost while offline",
+                overwritten - self.overwritten_logs
+            );
+            self.overwritten_logs = overwritten;
+        }
+        let dropped = MQTT_LOG_SINK.dropped();
+        if dropped > self.dropped_logs {
+            hlog_warn!(
+                LOGGER,
+                "{} log records were too large to publish",
+                dropped - self.dropped_logs
+            );
+            self.dropped_logs = dropped;
+        }
+    }
+
+    fn disconnected(&mut self) {
+        MQTT_LOG_SINK.set_online(false);
     }
 }
 
 #[embassy_executor::task]
-async fn mqtt_recv_task(stack: &'static Stack<Ethernet<'static, ETH, GenericSMI>>) {
+async fn mqtt_task(stack: &'static Stack<Ethernet<'static, ETH, GenericSMI>>) {
     let mut rx_buffer: [u8; 4096] = [0; 4096];
     let mut tx_buffer: [u8; 4096] = [0; 4096];
-    let mut socket = TcpSocket::new(&stack, &mut rx_buffer, &mut tx_buffer);
-    socket.set_timeout(Some(embassy_time::Duration::from_secs(600)));
-    info!("Connecting...");
-    match socket
-        .connect((Ipv4Address::new(169, 254, 195, 141), 1883))
-        .await
-    {
-        Ok(()) => {
-            log(LogLevel::Info, "Connected to Receive!").await;
-        }
-        Err(connection_error) => {
-            log(
-                LogLevel::Error,
-                format_string::show(
-                    &mut [0; 1024],
-                    format_args!("Error connecting: {:?}", connection_error),
-                )
-                .unwrap(),
-            )
-            .await;
-        }
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(embassy_time::Duration::from_secs(60)));
+    hlog_info!(LOGGER, "Connecting to MQTT broker...");
+    let config = MQTT_CONFIG;
+    let [a, b, c, d] = BROKER_IPV4;
+    let transport = TcpTransport {
+        socket,
+        endpoint: (Ipv4Address::new(a, b, c, d), config.broker_port),
     };
-    let mut config = ClientConfig::new(
-        rust_mqtt::client::client_config::MqttVersion::MQTTv5,
-        CountingRng(10000),
-    );
-    config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-    config.max_packet_size = 100;
-    config.add_client_id("receiver-stm-client");
+    let client_id: String<32> = unwrap!(config.client_id("board"));
+
     let mut recv_buffer = [0; 1024];
     let mut write_buffer = [0; 1024];
-    let client = MqttClient::<_, 5, _>::new(
-        socket,
+    let mut supervisor = MqttSupervisor::<_, _, _, _, _, 8>::new(
+        transport,
+        EmbassyDelay,
+        CountingRng(30000),
+        || config.client_config(client_id.as_str(), CountingRng(20000)),
+        config.namespace,
+        Backoff::new(500, 30_000),
         &mut write_buffer,
-        1024,
         &mut recv_buffer,
-        1024,
-        config,
     );
-    let mut mqtt_client = HypedMqttClient { client };
-    mqtt_client.connect_to_broker().await;
-
-    mqtt_client.subscribe("command_sender").await;
-    mqtt_client.subscribe("acceleration").await;
-
-    loop {
-        match mqtt_client.receive_message().await {
-            Ok((topic, message)) => {
-                log(
-                    LogLevel::Info,
-                    format_string::show(
-                        &mut [0; 1024],
-                        format_args!("Received message on topic {}: {}", topic, message),
-                    )
-                    .unwrap(),
-                )
-                .await
-            }
-            Err(err) => {
-                if err == rust_mqtt::packet::v5::reason_codes::ReasonCode::NetworkError {
-                    break;
-                }
-                log(
-                    LogLevel::Error,
-                    format_string::show(
-                        &mut [0; 1024],
-                        format_args!("Error receiving message: {:?}", err),
-                    )
-                    .unwrap(),
-                )
-                .await
-            }
+    // Only the base station's heartbeats are watched, so the board does not
+    // get its own back.
+    let base_station = TopicNamespace {
+        board: BASE_STATION_BOARD,
+        ..config.namespace
+    };
+    for content_type in ContentType::ALL {
+        for kind in [MqttTopics::StateRequest, MqttTopics::LogLevel] {
+            let filter = unwrap!(config.namespace.any_board(kind, content_type));
+            unwrap!(supervisor.add_subscription(filter));
         }
-        Timer::after(Duration::from_millis(100)).await;
+        let topic = unwrap!(base_station.topic(MqttTopics::Heartbeat, content_type));
+        unwrap!(supervisor.add_subscription(unwrap!(TopicFilter::new(&topic))));
     }
+    let handler = BoardHandler {
+        overwritten_logs: 0,
+        dropped_logs: 0,
+    };
+    supervisor
+        .run(&mut MultiplexedSession::new(
+            &SEND_CHANNEL,
+            EmbassyDelay,
+            uptime_ms,
+            config.keep_alive_secs,
+            handler,
+        ))
+        .await
 }
 
 #[embassy_executor::main]
@@ -263,7 +420,7 @@ async fn main(spawner: Spawner) -> ! {
     let p = embassy_stm32::init(config);
     spawner.spawn(button_task(p.PC13.degrade())).unwrap();
 
-    log(LogLevel::Info, "Hello World!").await;
+    hlog_info!(LOGGER, "Hello World!");
 
     let seed: u64 = 0xdeadbeef;
     let mac_addr: [u8; 6] = [0x00, 0x00, 0xDE, 0xAD, 0xBE, 0xEF];
@@ -295,11 +452,11 @@ async fn main(spawner: Spawner) -> ! {
 
     // Init network stack
     static STACK: StaticCell<Stack<Ethernet<'static, ETH, GenericSMI>>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<2>> = StaticCell::new();
     let stack = &*STACK.init(Stack::new(
         device,
         config,
-        RESOURCES.init(StackResources::<3>::new()),
+        RESOURCES.init(StackResources::<2>::new()),
         seed,
     ));
 
@@ -309,25 +466,11 @@ async fn main(spawner: Spawner) -> ! {
     // Ensure DHCP configuration is up before trying connect
     stack.wait_config_up().await;
 
-    log(LogLevel::Info, "Network stack initialized").await;
-    unwrap!(spawner.spawn(mqtt_send_task(stack)));
-    unwrap!(spawner.spawn(mqtt_recv_task(stack)));
-    unwrap!(spawner.spawn(five_seconds_task()));
+    hlog_info!(LOGGER, "Network stack initialized");
+    unwrap!(spawner.spawn(mqtt_task(stack)));
+    unwrap!(spawner.spawn(heartbeat_task()));
     loop {
-        SEND_CHANNEL
-            .send(MqttMessage {
-                topic: MqttTopics::to_string(&MqttTopics::Acceleration),
-                payload: String::<512>::from_str(
-                    serde_json_core::to_string::<U512, ButtonMqttMessage>(&ButtonMqttMessage {
-                        task_id: 1,
-                        status: false,
-                    })
-                    .unwrap()
-                    .as_str(),
-                )
-                .unwrap(),
-            })
-            .await;
+        HEARTBEAT.check_in(TASK_MAIN);
         Timer::after(Duration::from_millis(1000)).await;
     }
 }